@@ -0,0 +1,57 @@
+use serde_json::{json, Value};
+
+/// Оборачивает обычный текст в json компонент чата
+pub fn text(text: &str) -> String {
+	json!({ "text": text }).to_string()
+}
+
+/// Переводимый компонент, клиент подставит текст на своём языке.
+/// Аргументы - json компоненты, ключи стоит брать из языковых файлов клиента
+pub fn translate(key: &str, with: &[String]) -> String {
+	let with = with
+		.iter()
+		.map(|arg| {
+			serde_json::from_str::<Value>(arg).unwrap_or_else(|_| Value::String(arg.clone()))
+		})
+		.collect::<Vec<_>>();
+	json!({ "translate": key, "with": with }).to_string()
+}
+
+/// Ошибка без перевода, "Internal Exception: ..."
+pub fn internal_error(error: &str) -> String {
+	translate("disconnect.genericReason", &[text(error)])
+}
+
+/// Компонент из текстового префикса и переданного как есть json компонента
+pub fn prefixed(prefix: &str, component: &str) -> String {
+	let component = serde_json::from_str::<Value>(component)
+		.unwrap_or_else(|_| Value::String(component.to_owned()));
+	json!({ "text": prefix, "extra": [component] }).to_string()
+}
+
+/// Вытаскивает из json компонента текст без форматирования
+pub fn plain_text(component: &str) -> String {
+	fn collect(value: &Value, out: &mut String) {
+		match value {
+			Value::String(s) => out.push_str(s),
+			Value::Array(parts) => parts.iter().for_each(|p| collect(p, out)),
+			Value::Object(obj) => {
+				if let Some(text) = obj.get("text") {
+					collect(text, out);
+				}
+				if let Some(extra) = obj.get("extra") {
+					collect(extra, out);
+				}
+			}
+			_ => {}
+		}
+	}
+	match serde_json::from_str::<Value>(component) {
+		Ok(value) => {
+			let mut out = String::new();
+			collect(&value, &mut out);
+			out
+		}
+		Err(_) => component.to_owned(),
+	}
+}
@@ -0,0 +1,159 @@
+use std::collections::HashSet;
+
+use tokio::io::{self, AsyncWrite};
+
+use crate::{
+	entity_map::EntityMap,
+	ext::{MaybeCompressed, MinecraftAsyncWriteExt},
+	protocol::{
+		play::{
+			BossBar, ChangeGameState, JoinGame, PlayerListItem, Respawn, ScoreboardObjective, Teams,
+		},
+		Packet, VarInt,
+	},
+};
+
+/// Состояние клиента, которое накидал ему текущий сервер, и которое нужно убрать при переходе на другой
+#[derive(Default)]
+pub struct ClientState {
+	/// Измерение из первого JoinGame, None - клиент ещё не в игре
+	dimension: Option<i32>,
+	objectives: HashSet<String>,
+	teams: HashSet<String>,
+	boss_bars: HashSet<u128>,
+	players: HashSet<u128>,
+	pub entities: EntityMap,
+}
+
+impl ClientState {
+	/// Запоминает то, что нужно будет очистить при смене сервера
+	pub fn track(&mut self, id: i32, packet: &MaybeCompressed<'_>) -> io::Result<()> {
+		match id {
+			ScoreboardObjective::ID => {
+				let objective = packet.peek::<ScoreboardObjective>()?;
+				match objective.mode {
+					0 => self.objectives.insert(objective.name),
+					1 => self.objectives.remove(&objective.name),
+					_ => false,
+				};
+			}
+			Teams::ID => {
+				let team = packet.peek::<Teams>()?;
+				match team.mode {
+					0 => self.teams.insert(team.name),
+					1 => self.teams.remove(&team.name),
+					_ => false,
+				};
+			}
+			BossBar::ID => {
+				let bar = packet.peek::<BossBar>()?;
+				match bar.action.0 {
+					0 => self.boss_bars.insert(bar.uuid),
+					1 => self.boss_bars.remove(&bar.uuid),
+					_ => false,
+				};
+			}
+			PlayerListItem::ID => {
+				let list = packet.peek::<PlayerListItem>()?;
+				match list.action.0 {
+					PlayerListItem::ADD_PLAYER => self.players.extend(list.players),
+					PlayerListItem::REMOVE_PLAYER => {
+						for player in list.players {
+							self.players.remove(&player);
+						}
+					}
+					_ => {}
+				}
+			}
+			_ => {}
+		}
+		Ok(())
+	}
+
+	/// Обрабатывает JoinGame очередного сервера. Первый пересылается как есть, на последующие
+	/// клиент перезагружает мир через Respawn в другое измерение, и затем в нужное
+	pub async fn join<W: AsyncWrite + Unpin + Send>(
+		&mut self,
+		join: JoinGame,
+		compression: Option<i32>,
+		user: &mut W,
+	) -> io::Result<()> {
+		if self.dimension.is_none() {
+			self.dimension = Some(join.dimension);
+			self.entities = EntityMap {
+				client: join.entity_id,
+				server: join.entity_id,
+			};
+			return user.write_packet(compression, &join).await;
+		}
+		self.entities.server = join.entity_id;
+		self.reset(compression, user).await?;
+		user.write_packet(
+			compression,
+			&Respawn {
+				dimension: if join.dimension == 0 { -1 } else { 0 },
+				difficulty: join.difficulty,
+				game_mode: join.game_mode,
+				level_type: join.level_type.clone(),
+			},
+		)
+		.await?;
+		user.write_packet(
+			compression,
+			&Respawn {
+				dimension: join.dimension,
+				difficulty: join.difficulty,
+				game_mode: join.game_mode,
+				level_type: join.level_type,
+			},
+		)
+		.await?;
+		self.dimension = Some(join.dimension);
+		Ok(())
+	}
+
+	/// Убирает у клиента всё, что было создано предыдущим сервером
+	async fn reset<W: AsyncWrite + Unpin + Send>(
+		&mut self,
+		compression: Option<i32>,
+		user: &mut W,
+	) -> io::Result<()> {
+		for name in self.objectives.drain() {
+			user.write_packet(compression, &ScoreboardObjective { name, mode: 1 })
+				.await?;
+		}
+		for name in self.teams.drain() {
+			user.write_packet(compression, &Teams { name, mode: 1 })
+				.await?;
+		}
+		for uuid in self.boss_bars.drain() {
+			user.write_packet(
+				compression,
+				&BossBar {
+					uuid,
+					action: VarInt(1),
+				},
+			)
+			.await?;
+		}
+		if !self.players.is_empty() {
+			user.write_packet(
+				compression,
+				&PlayerListItem {
+					action: VarInt(PlayerListItem::REMOVE_PLAYER),
+					players: self.players.drain().collect(),
+				},
+			)
+			.await?;
+		}
+		for (reason, value) in [
+			(ChangeGameState::END_RAINING, 0.0),
+			(ChangeGameState::RAIN_LEVEL, 0.0),
+			(ChangeGameState::THUNDER_LEVEL, 0.0),
+		] {
+			user.write_packet(compression, &ChangeGameState { reason, value })
+				.await?;
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,473 @@
+use std::{
+	collections::{BTreeMap, HashMap},
+	str::FromStr,
+	sync::{Arc, RwLock},
+};
+
+use futures::future::{BoxFuture, FutureExt};
+use tokio::net::lookup_host;
+use uuid::Uuid;
+
+use crate::{
+	permissions::Nodes,
+	plugin::TargetServer,
+	server::{ConnectedPlayer, ProxyServer},
+};
+
+/// С чем вызвана команда
+#[derive(Clone)]
+pub struct CommandContext {
+	pub player: Arc<ConnectedPlayer>,
+	pub proxy: ProxyServer,
+}
+impl CommandContext {
+	pub fn reply(&self, message: &str) -> bool {
+		self.player.handle().message(message)
+	}
+}
+
+/// Аргументы команды, разбираемые из слов после её имени.
+/// Реализован для кортежей из типов с `FromStr`, лишние слова - ошибка
+pub trait Arguments: Sized + Send + 'static {
+	fn parse(args: &[&str]) -> Option<Self>;
+}
+macro_rules! impl_arguments {
+	($($name:ident),*) => {
+		impl<$($name: FromStr + Send + 'static),*> Arguments for ($($name,)*) {
+			#[allow(unused_mut)]
+			fn parse(args: &[&str]) -> Option<Self> {
+				let mut args = args.iter();
+				let parsed = ($(args.next()?.parse::<$name>().ok()?,)*);
+				if args.next().is_some() {
+					return None;
+				}
+				Some(parsed)
+			}
+		}
+	};
+}
+impl_arguments!();
+impl_arguments!(A);
+impl_arguments!(A, B);
+impl_arguments!(A, B, C);
+impl_arguments!(A, B, C, D);
+/// Все слова как есть, для команд с подкомандами
+impl Arguments for Vec<String> {
+	fn parse(args: &[&str]) -> Option<Self> {
+		Some(args.iter().map(|arg| arg.to_string()).collect())
+	}
+}
+
+/// Ошибка - текст, который увидит игрок
+type Executor = Box<
+	dyn Fn(CommandContext, &[&str]) -> Option<BoxFuture<'static, Result<(), String>>> + Send + Sync,
+>;
+/// Получает уже введённые аргументы, последний может быть недописанным
+type Completer = Box<dyn Fn(&CommandContext, &[&str]) -> Vec<String> + Send + Sync>;
+
+pub struct Command {
+	name: String,
+	aliases: Vec<String>,
+	permission: Option<String>,
+	usage: String,
+	description: String,
+	executor: Executor,
+	completer: Option<Completer>,
+}
+impl Command {
+	pub fn new<A, F, Fut>(name: impl Into<String>, handler: F) -> Self
+	where
+		A: Arguments,
+		F: Fn(CommandContext, A) -> Fut + Send + Sync + 'static,
+		Fut: std::future::Future<Output = Result<(), String>> + Send + 'static,
+	{
+		Self {
+			name: name.into().to_lowercase(),
+			aliases: Vec::new(),
+			permission: None,
+			usage: String::new(),
+			description: String::new(),
+			executor: Box::new(move |ctx, args| Some(handler(ctx, A::parse(args)?).boxed())),
+			completer: None,
+		}
+	}
+	pub fn alias(mut self, alias: impl Into<String>) -> Self {
+		self.aliases.push(alias.into().to_lowercase());
+		self
+	}
+	pub fn permission(mut self, node: impl Into<String>) -> Self {
+		self.permission = Some(node.into());
+		self
+	}
+	/// Аргументы, например `<host>`
+	pub fn usage(mut self, usage: impl Into<String>) -> Self {
+		self.usage = usage.into();
+		self
+	}
+	pub fn description(mut self, description: impl Into<String>) -> Self {
+		self.description = description.into();
+		self
+	}
+	pub fn completer<F>(mut self, completer: F) -> Self
+	where
+		F: Fn(&CommandContext, &[&str]) -> Vec<String> + Send + Sync + 'static,
+	{
+		self.completer = Some(Box::new(completer));
+		self
+	}
+
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+	pub fn permission_node(&self) -> Option<&str> {
+		self.permission.as_deref()
+	}
+	pub fn allows(&self, ctx: &CommandContext) -> bool {
+		match &self.permission {
+			Some(node) => ctx.proxy.has_permission(&ctx.player, node),
+			None => true,
+		}
+	}
+	pub fn usage_line(&self) -> String {
+		if self.usage.is_empty() {
+			format!("/{}", self.name)
+		} else {
+			format!("/{} {}", self.name, self.usage)
+		}
+	}
+}
+
+/// Команды прокси, ключ - имя или алиас в нижнем регистре
+#[derive(Default)]
+pub struct Commands {
+	commands: RwLock<HashMap<String, Arc<Command>>>,
+}
+impl Commands {
+	/// Заменяет ранее зарегистрированные команды с теми же именами и алиасами
+	pub fn register(&self, command: Command) {
+		let command = Arc::new(command);
+		let mut commands = self.commands.write().unwrap();
+		for alias in &command.aliases {
+			commands.insert(alias.clone(), command.clone());
+		}
+		commands.insert(command.name.clone(), command);
+	}
+	pub fn get(&self, name: &str) -> Option<Arc<Command>> {
+		self.commands
+			.read()
+			.unwrap()
+			.get(&name.to_lowercase())
+			.cloned()
+	}
+	/// Без повторов из-за алиасов, по алфавиту
+	pub fn list(&self) -> Vec<Arc<Command>> {
+		self.commands
+			.read()
+			.unwrap()
+			.values()
+			.map(|command| (command.name.clone(), command.clone()))
+			.collect::<BTreeMap<_, _>>()
+			.into_values()
+			.collect()
+	}
+
+	/// Сообщение из чата без `/`. false - такой команды у прокси нет, сообщение уходит серверу.
+	/// Сама команда выполняется в фоне, не задерживая пакеты игрока
+	pub fn dispatch(&self, ctx: CommandContext, line: &str) -> bool {
+		let mut words = line.split_whitespace();
+		let command = match words.next().and_then(|name| self.get(name)) {
+			Some(command) => command,
+			None => return false,
+		};
+		if !command.allows(&ctx) {
+			ctx.reply("You don't have permission to use this command");
+			return true;
+		}
+		let args = words.collect::<Vec<_>>();
+		match (command.executor)(ctx.clone(), &args) {
+			Some(execution) => {
+				tokio::spawn(async move {
+					if let Err(message) = execution.await {
+						ctx.reply(&message);
+					}
+				});
+			}
+			None => {
+				ctx.reply(&format!("Usage: {}", command.usage_line()));
+			}
+		}
+		true
+	}
+
+	/// Варианты для недописанного текста из чата
+	pub fn complete(&self, ctx: &CommandContext, text: &str) -> Completion {
+		if let Some(line) = text.strip_prefix('/') {
+			let mut words = line.split(' ').collect::<Vec<_>>();
+			if words.len() == 1 {
+				let prefix = words[0].to_lowercase();
+				return Completion::Merge(
+					self.commands
+						.read()
+						.unwrap()
+						.iter()
+						.filter(|(name, command)| name.starts_with(&prefix) && command.allows(ctx))
+						.map(|(name, _)| format!("/{}", name))
+						.collect(),
+				);
+			}
+			if let Some(command) = self.get(words.remove(0)) {
+				return Completion::Proxy(match &command.completer {
+					Some(completer) if command.allows(ctx) => completer(ctx, &words),
+					_ => Vec::new(),
+				});
+			}
+		}
+		// Имена игроков со всех серверов прокси
+		let prefix = text.rsplit(' ').next().unwrap_or_default().to_lowercase();
+		Completion::Merge(
+			ctx.proxy
+				.players()
+				.into_iter()
+				.filter(|player| player.username.to_lowercase().starts_with(&prefix))
+				.map(|player| player.username.clone())
+				.collect(),
+		)
+	}
+}
+
+/// Как ответить на Tab-Complete
+pub enum Completion {
+	/// Текст - команда прокси, сервер не спрашивается
+	Proxy(Vec<String>),
+	/// Ответ сервера дополняется этими вариантами
+	Merge(Vec<String>),
+}
+
+/// Команды, доступные без плагинов
+pub fn register_builtins(commands: &Commands) {
+	commands.register(
+		Command::new("proxy-ping", |ctx, ()| async move {
+			ctx.reply("Pong");
+			Ok(())
+		})
+		.description("Check that the proxy is alive"),
+	);
+	commands.register(
+		Command::new("proxy-goto", |ctx, (host,): (String,)| async move {
+			let target = resolve_target(&host).await?;
+			ctx.player.handle().connect(target);
+			Ok(())
+		})
+		.usage("<host[:port]>")
+		.description("Move to another server")
+		.permission("proxy.command.goto"),
+	);
+	commands.register(
+		Command::new("proxy-perms", |ctx, args: Vec<String>| async move {
+			let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+			let permissions = ctx.proxy.permissions();
+			let result = match args[..] {
+				["reload"] => permissions.reload(),
+				["user", player, "set", node, value] => {
+					let uuid = resolve_uuid(&ctx.proxy, player)?;
+					let value = parse_value(value)?;
+					permissions.update(|config| {
+						set_node(
+							&mut config.players.entry(uuid).or_default().permissions,
+							node,
+							value,
+						)
+					})
+				}
+				["user", player, "group", action @ ("add" | "remove"), group] => {
+					let uuid = resolve_uuid(&ctx.proxy, player)?;
+					permissions.update(|config| {
+						let groups = &mut config.players.entry(uuid).or_default().groups;
+						groups.retain(|g| g != group);
+						if action == "add" {
+							groups.push(group.to_owned());
+						}
+					})
+				}
+				["group", group, "set", node, value] => {
+					let value = parse_value(value)?;
+					permissions.update(|config| {
+						set_node(
+							&mut config
+								.groups
+								.entry(group.to_owned())
+								.or_default()
+								.permissions,
+							node,
+							value,
+						)
+					})
+				}
+				["group", group, "inherit", action @ ("add" | "remove"), parent] => permissions
+					.update(|config| {
+						let inherits =
+							&mut config.groups.entry(group.to_owned()).or_default().inherits;
+						inherits.retain(|g| g != parent);
+						if action == "add" {
+							inherits.push(parent.to_owned());
+						}
+					}),
+				_ => return Err(format!("Usage: /proxy-perms {}", PERMS_USAGE)),
+			};
+			result.map_err(|e| format!("Failed to update permissions: {}", e))?;
+			ctx.reply("Permissions updated");
+			Ok(())
+		})
+		.usage(PERMS_USAGE)
+		.description("Change player and group permissions")
+		.permission("proxy.command.perms")
+		.completer(|ctx, args| match args {
+			[sub] => ["user", "group", "reload"]
+				.iter()
+				.filter(|s| s.starts_with(sub))
+				.map(|s| s.to_string())
+				.collect(),
+			["user", player] => ctx
+				.proxy
+				.players()
+				.into_iter()
+				.filter(|p| {
+					p.username
+						.to_lowercase()
+						.starts_with(&player.to_lowercase())
+				})
+				.map(|p| p.username.clone())
+				.collect(),
+			[_, _, action] => ["set", "group", "inherit"]
+				.iter()
+				.filter(|s| s.starts_with(action))
+				.map(|s| s.to_string())
+				.collect(),
+			_ => Vec::new(),
+		}),
+	);
+	commands.register(
+		Command::new("proxy-help", |ctx, ()| async move {
+			for command in ctx.proxy.commands().list() {
+				if !command.allows(&ctx) {
+					continue;
+				}
+				if command.description.is_empty() {
+					ctx.reply(&command.usage_line());
+				} else {
+					ctx.reply(&format!(
+						"{} - {}",
+						command.usage_line(),
+						command.description
+					));
+				}
+			}
+			Ok(())
+		})
+		.description("List proxy commands"),
+	);
+}
+
+const PERMS_USAGE: &str = "reload | user <player|uuid> set <node> <true|false|unset> | \
+	user <player|uuid> group <add|remove> <group> | group <group> set <node> <true|false|unset> | \
+	group <group> inherit <add|remove> <parent>";
+
+/// Игрок на прокси, либо UUID как есть, чтобы менять права тех, кого сейчас нет
+fn resolve_uuid(proxy: &ProxyServer, player: &str) -> Result<String, String> {
+	if let Some(player) = proxy.player_by_name(player) {
+		return Ok(player.uuid.clone());
+	}
+	Uuid::parse_str(player)
+		.map(|uuid| uuid.to_hyphenated().to_string())
+		.map_err(|_| format!("Player {} is not online", player))
+}
+
+fn parse_value(value: &str) -> Result<Option<bool>, String> {
+	match value {
+		"true" => Ok(Some(true)),
+		"false" => Ok(Some(false)),
+		"unset" => Ok(None),
+		_ => Err(format!("Expected true, false or unset, got {}", value)),
+	}
+}
+
+fn set_node(nodes: &mut Nodes, node: &str, value: Option<bool>) {
+	match value {
+		Some(value) => nodes.insert(node.to_owned(), value),
+		None => nodes.remove(node),
+	};
+}
+
+const DEFAULT_PORT: u16 = 25565;
+
+/// `host`, `host:port`, `[ipv6]` или `[ipv6]:port`, без порта - 25565
+fn split_host_port(host: &str) -> Result<(&str, u16), String> {
+	let (name, port) = match host.strip_prefix('[') {
+		Some(rest) => {
+			let (name, rest) = rest
+				.split_once(']')
+				.ok_or_else(|| format!("Bad address {}", host))?;
+			match rest {
+				"" => (name, None),
+				_ => (
+					name,
+					Some(
+						rest.strip_prefix(':')
+							.ok_or_else(|| format!("Bad address {}", host))?,
+					),
+				),
+			}
+		}
+		None => match host.rsplit_once(':') {
+			// Несколько двоеточий без скобок - IPv6 без порта
+			Some((name, port)) if !name.contains(':') => (name, Some(port)),
+			_ => (host, None),
+		},
+	};
+	let port = match port {
+		Some(port) => port.parse().map_err(|_| format!("Bad port in {}", host))?,
+		None => DEFAULT_PORT,
+	};
+	Ok((name, port))
+}
+
+async fn resolve_target(host: &str) -> Result<TargetServer, String> {
+	let (name, port) = split_host_port(host)?;
+	let addr = lookup_host((name, port))
+		.await
+		.ok()
+		.and_then(|mut addrs| addrs.next())
+		.ok_or_else(|| format!("Unknown host {}", host))?;
+	Ok(TargetServer {
+		addr,
+		handshake_address: name.to_owned(),
+		handshake_port: port as i16,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn host_and_port() {
+		assert_eq!(
+			split_host_port("mc.example.com"),
+			Ok(("mc.example.com", 25565))
+		);
+		assert_eq!(
+			split_host_port("mc.example.com:25577"),
+			Ok(("mc.example.com", 25577))
+		);
+		assert_eq!(split_host_port("127.0.0.1:25566"), Ok(("127.0.0.1", 25566)));
+		assert_eq!(split_host_port("[2001:db8::1]"), Ok(("2001:db8::1", 25565)));
+		assert_eq!(
+			split_host_port("[2001:db8::1]:25566"),
+			Ok(("2001:db8::1", 25566))
+		);
+		assert_eq!(split_host_port("2001:db8::1"), Ok(("2001:db8::1", 25565)));
+		assert!(split_host_port("mc.example.com:port").is_err());
+		assert!(split_host_port("[2001:db8::1").is_err());
+		assert!(split_host_port("[2001:db8::1]25566").is_err());
+	}
+}
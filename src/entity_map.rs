@@ -0,0 +1,561 @@
+use std::io::{Cursor, Read};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use tokio::io;
+
+use crate::ext::{MinecraftReadExt, MinecraftWriteExt};
+
+/// Клиент знает себя по ID сущности из первого JoinGame, а текущий сервер - по своему.
+/// Эти два ID меняются местами во всех пакетах, где встречаются ID сущностей
+#[derive(Default)]
+pub struct EntityMap {
+	pub client: i32,
+	pub server: i32,
+}
+
+impl EntityMap {
+	fn swap(&self, id: i32) -> i32 {
+		if id == self.server {
+			self.client
+		} else if id == self.client {
+			self.server
+		} else {
+			id
+		}
+	}
+
+	pub fn rewrites_clientbound(&self, packet_id: i32) -> bool {
+		self.client != self.server
+			&& matches!(
+				packet_id,
+				0x00 | 0x03
+					| 0x05 | 0x06 | 0x08
+					| 0x1B | 0x25..=0x28
+					| 0x2D | 0x30 | 0x32
+					| 0x33 | 0x36 | 0x39
+					| 0x3C..=0x3F | 0x43
+					| 0x4B | 0x4C | 0x4E
+					| 0x4F
+			)
+	}
+
+	pub fn rewrites_serverbound(&self, packet_id: i32) -> bool {
+		self.client != self.server && matches!(packet_id, 0x0A | 0x15)
+	}
+
+	pub fn rewrite_clientbound(&self, packet_id: i32, data: &[u8]) -> io::Result<Vec<u8>> {
+		let mut p = Rewrite::new(data);
+		match packet_id {
+			// Spawn Object
+			0x00 => {
+				p.varint(|id| self.swap(id))?;
+				// UUID
+				p.copy(16)?;
+				let kind = p.byte()?;
+				// Координаты, поворот
+				p.copy(8 * 3 + 2)?;
+				match kind {
+					// Стрелы, ID стрелявшего + 1
+					60 | 91 => p.int(|id| self.swap(id - 1) + 1)?,
+					// Поплавок, ID владельца
+					90 => p.int(|id| self.swap(id))?,
+					_ => 0,
+				};
+			}
+			// Entity Status
+			0x1B => {
+				p.int(|id| self.swap(id))?;
+			}
+			// Combat Event
+			0x2D => match p.varint(|v| v)? {
+				// End combat
+				1 => {
+					p.varint(|v| v)?;
+					p.int(|id| self.swap(id))?;
+				}
+				// Entity dead
+				2 => {
+					p.varint(|id| self.swap(id))?;
+					p.int(|id| self.swap(id))?;
+				}
+				_ => {}
+			},
+			// Destroy Entities
+			0x32 => {
+				let count = p.varint(|v| v)?;
+				for _ in 0..count {
+					p.varint(|id| self.swap(id))?;
+				}
+			}
+			// Entity Metadata
+			0x3C => {
+				p.varint(|id| self.swap(id))?;
+				self.rewrite_metadata(&mut p)?;
+			}
+			// Attach Entity
+			0x3D => {
+				p.int(|id| self.swap(id))?;
+				p.int(|id| self.swap(id))?;
+			}
+			// Set Passengers
+			0x43 => {
+				p.varint(|id| self.swap(id))?;
+				let count = p.varint(|v| v)?;
+				for _ in 0..count {
+					p.varint(|id| self.swap(id))?;
+				}
+			}
+			// Collect Item
+			0x4B => {
+				p.varint(|id| self.swap(id))?;
+				p.varint(|id| self.swap(id))?;
+			}
+			// Во всех остальных ID сущности идёт первым полем
+			_ => {
+				p.varint(|id| self.swap(id))?;
+			}
+		}
+		p.finish()
+	}
+
+	pub fn rewrite_serverbound(&self, _packet_id: i32, data: &[u8]) -> io::Result<Vec<u8>> {
+		// Use Entity и Entity Action начинаются с ID сущности
+		let mut p = Rewrite::new(data);
+		p.varint(|id| self.swap(id))?;
+		p.finish()
+	}
+
+	/// ID сущностей встречаются в метаданных поплавка (6, ID + 1), фейерверка (7) и стража (13).
+	/// 0 во всех трёх означает "никого" и не меняется
+	fn rewrite_metadata(&self, p: &mut Rewrite<'_>) -> io::Result<()> {
+		loop {
+			let index = p.byte()?;
+			if index == 0xff {
+				return Ok(());
+			}
+			let kind = p.varint(|v| v)?;
+			match kind {
+				// Byte, Boolean
+				0 | 6 => p.copy(1)?,
+				// VarInt
+				1 => {
+					p.varint(|id| match index {
+						6 if id != 0 => self.swap(id - 1) + 1,
+						7 | 13 if id != 0 => self.swap(id),
+						_ => id,
+					})?;
+				}
+				// Float
+				2 => p.copy(4)?,
+				// String, Chat
+				3 | 4 => {
+					let len = p.varint(|v| v)?;
+					p.copy(length(len)?)?;
+				}
+				// Slot
+				5 => {
+					let item = p.short()?;
+					if item != -1 {
+						// Count, damage
+						p.copy(3)?;
+						p.nbt()?;
+					}
+				}
+				// Rotation
+				7 => p.copy(12)?,
+				// Position
+				8 => p.copy(8)?,
+				// OptPosition
+				9 => {
+					if p.byte()? != 0 {
+						p.copy(8)?;
+					}
+				}
+				// Direction, OptBlockID
+				10 | 12 => {
+					p.varint(|v| v)?;
+				}
+				// OptUUID
+				11 => {
+					if p.byte()? != 0 {
+						p.copy(16)?;
+					}
+				}
+				// NBT
+				13 => p.nbt()?,
+				_ => {
+					return Err(io::Error::new(
+						io::ErrorKind::InvalidData,
+						format!("unknown metadata type {}", kind),
+					))
+				}
+			}
+		}
+	}
+}
+
+fn length(len: i32) -> io::Result<usize> {
+	if len < 0 {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidData,
+			format!("negative length {}", len),
+		));
+	}
+	Ok(len as usize)
+}
+
+/// Читает поля из исходного пакета, и сразу пишет их (возможно изменёнными) в новый
+struct Rewrite<'i> {
+	input: Cursor<&'i [u8]>,
+	out: Vec<u8>,
+}
+impl<'i> Rewrite<'i> {
+	fn new(input: &'i [u8]) -> Self {
+		Self {
+			input: Cursor::new(input),
+			out: Vec::with_capacity(input.len() + 5),
+		}
+	}
+	fn varint(&mut self, f: impl FnOnce(i32) -> i32) -> io::Result<i32> {
+		let value = self.input.read_varint()?.ans;
+		self.out.write_varint(f(value))?;
+		Ok(value)
+	}
+	fn int(&mut self, f: impl FnOnce(i32) -> i32) -> io::Result<i32> {
+		let value = self.input.read_i32::<BigEndian>()?;
+		self.out.write_i32::<BigEndian>(f(value))?;
+		Ok(value)
+	}
+	fn short(&mut self) -> io::Result<i16> {
+		let value = self.input.read_i16::<BigEndian>()?;
+		self.out.write_i16::<BigEndian>(value)?;
+		Ok(value)
+	}
+	fn byte(&mut self) -> io::Result<u8> {
+		let value = self.input.read_u8()?;
+		self.out.push(value);
+		Ok(value)
+	}
+	/// Длина проверяется по остатку пакета до выделения памяти
+	fn copy(&mut self, len: usize) -> io::Result<()> {
+		let remaining = self.input.get_ref().len() as u64 - self.input.position();
+		if len as u64 > remaining {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				"field is longer than the packet",
+			));
+		}
+		let start = self.out.len();
+		self.out.resize(start + len, 0);
+		self.input.read_exact(&mut self.out[start..])
+	}
+	/// Именованный тег (обычно compound), либо TAG_End
+	fn nbt(&mut self) -> io::Result<()> {
+		let tag = self.byte()?;
+		if tag != 0 {
+			self.nbt_string()?;
+			self.nbt_payload(tag)?;
+		}
+		Ok(())
+	}
+	fn nbt_string(&mut self) -> io::Result<()> {
+		let len = self.short()? as u16;
+		self.copy(len as usize)
+	}
+	fn nbt_array(&mut self, element: usize) -> io::Result<()> {
+		let len = self.int(|v| v)?;
+		self.copy(length(len)?.saturating_mul(element))
+	}
+	fn nbt_payload(&mut self, tag: u8) -> io::Result<()> {
+		match tag {
+			// Byte, Short, Int, Long, Float, Double
+			1 => self.copy(1),
+			2 => self.copy(2),
+			3 | 5 => self.copy(4),
+			4 | 6 => self.copy(8),
+			7 => self.nbt_array(1),
+			8 => self.nbt_string(),
+			// List
+			9 => {
+				let element = self.byte()?;
+				let len = self.int(|v| v)?;
+				for _ in 0..len {
+					self.nbt_payload(element)?;
+				}
+				Ok(())
+			}
+			// Compound
+			10 => loop {
+				let tag = self.byte()?;
+				if tag == 0 {
+					return Ok(());
+				}
+				self.nbt_string()?;
+				self.nbt_payload(tag)?;
+			},
+			11 => self.nbt_array(4),
+			12 => self.nbt_array(8),
+			_ => Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				format!("unknown nbt tag {}", tag),
+			)),
+		}
+	}
+	/// Остаток пакета копируется как есть
+	fn finish(mut self) -> io::Result<Vec<u8>> {
+		self.input.read_to_end(&mut self.out)?;
+		Ok(self.out)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const CLIENT: i32 = 1;
+	const SERVER: i32 = 500;
+	const OTHER: i32 = 42;
+
+	fn map() -> EntityMap {
+		EntityMap {
+			client: CLIENT,
+			server: SERVER,
+		}
+	}
+
+	/// Сборщик тела пакета
+	#[derive(Default)]
+	struct Packet(Vec<u8>);
+	impl Packet {
+		fn varint(mut self, value: i32) -> Self {
+			self.0.write_varint(value).unwrap();
+			self
+		}
+		fn int(mut self, value: i32) -> Self {
+			self.0.write_i32::<BigEndian>(value).unwrap();
+			self
+		}
+		fn short(mut self, value: i16) -> Self {
+			self.0.write_i16::<BigEndian>(value).unwrap();
+			self
+		}
+		fn bytes(mut self, bytes: &[u8]) -> Self {
+			self.0.extend_from_slice(bytes);
+			self
+		}
+		fn string(self, value: &str) -> Self {
+			self.varint(value.len() as i32).bytes(value.as_bytes())
+		}
+		fn nbt_string(self, value: &str) -> Self {
+			self.short(value.len() as i16).bytes(value.as_bytes())
+		}
+	}
+
+	fn clientbound(packet_id: i32, packet: Packet) -> Vec<u8> {
+		assert!(map().rewrites_clientbound(packet_id));
+		map().rewrite_clientbound(packet_id, &packet.0).unwrap()
+	}
+
+	fn spawn_object(id: i32, kind: u8, data: i32) -> Packet {
+		Packet::default()
+			.varint(id)
+			.bytes(&[0xab; 16])
+			.bytes(&[kind])
+			.bytes(&[0x11; 8 * 3 + 2])
+			.int(data)
+			// Скорость
+			.short(1)
+			.short(2)
+			.short(3)
+	}
+
+	#[test]
+	fn spawn_object_arrow_shooter() {
+		for kind in &[60, 91] {
+			let rewritten = clientbound(0x00, spawn_object(OTHER, *kind, SERVER + 1));
+			assert_eq!(rewritten, spawn_object(OTHER, *kind, CLIENT + 1).0);
+			let rewritten = clientbound(0x00, spawn_object(OTHER, *kind, OTHER + 1));
+			assert_eq!(rewritten, spawn_object(OTHER, *kind, OTHER + 1).0);
+		}
+	}
+
+	#[test]
+	fn spawn_object_bobber_owner_and_self() {
+		let rewritten = clientbound(0x00, spawn_object(OTHER, 90, SERVER));
+		assert_eq!(rewritten, spawn_object(OTHER, 90, CLIENT).0);
+		// У остальных объектов data - не ID
+		let rewritten = clientbound(0x00, spawn_object(SERVER, 2, SERVER));
+		assert_eq!(rewritten, spawn_object(CLIENT, 2, SERVER).0);
+	}
+
+	fn metadata(id: i32, hooked: i32, firework_user: i32, guardian_target: i32) -> Packet {
+		Packet::default()
+			.varint(id)
+			// Byte
+			.bytes(&[0, 0])
+			.bytes(&[0x20])
+			// Slot с NBT
+			.bytes(&[2])
+			.varint(5)
+			.short(261)
+			.bytes(&[1, 0, 0])
+			.bytes(&[10])
+			.nbt_string("")
+			.bytes(&[10])
+			.nbt_string("display")
+			.bytes(&[8])
+			.nbt_string("Name")
+			.nbt_string("Bow")
+			.bytes(&[0])
+			.bytes(&[9])
+			.nbt_string("ench")
+			.bytes(&[3])
+			.int(2)
+			.int(48)
+			.int(49)
+			.bytes(&[11])
+			.nbt_string("ints")
+			.int(2)
+			.int(7)
+			.int(8)
+			.bytes(&[0])
+			// Поплавок: ID + 1, 0 - никого
+			.bytes(&[6])
+			.varint(1)
+			.varint(hooked)
+			// Фейерверк
+			.bytes(&[7])
+			.varint(1)
+			.varint(firework_user)
+			// String
+			.bytes(&[3])
+			.varint(3)
+			.string("name")
+			// Страж
+			.bytes(&[13])
+			.varint(1)
+			.varint(guardian_target)
+			.bytes(&[0xff])
+	}
+
+	#[test]
+	fn entity_metadata() {
+		let rewritten = clientbound(0x3C, metadata(SERVER, SERVER + 1, SERVER, CLIENT));
+		assert_eq!(rewritten, metadata(CLIENT, CLIENT + 1, CLIENT, SERVER).0);
+		let rewritten = clientbound(0x3C, metadata(OTHER, 0, OTHER, OTHER));
+		assert_eq!(rewritten, metadata(OTHER, 0, OTHER, OTHER).0);
+	}
+
+	#[test]
+	fn entity_metadata_zero_is_nobody() {
+		let map = EntityMap {
+			client: 0,
+			server: SERVER,
+		};
+		let packet = metadata(OTHER, 0, 0, 0);
+		assert_eq!(map.rewrite_clientbound(0x3C, &packet.0).unwrap(), packet.0);
+	}
+
+	#[test]
+	fn entity_metadata_unknown_type_is_error() {
+		let packet = Packet::default().varint(OTHER).bytes(&[0]).varint(99);
+		assert!(map().rewrite_clientbound(0x3C, &packet.0).is_err());
+	}
+
+	#[test]
+	fn destroy_entities() {
+		let packet = Packet::default()
+			.varint(3)
+			.varint(SERVER)
+			.varint(CLIENT)
+			.varint(OTHER);
+		let expected = Packet::default()
+			.varint(3)
+			.varint(CLIENT)
+			.varint(SERVER)
+			.varint(OTHER);
+		assert_eq!(clientbound(0x32, packet), expected.0);
+	}
+
+	#[test]
+	fn combat_event() {
+		let dead = |player, killer| {
+			Packet::default()
+				.varint(2)
+				.varint(player)
+				.int(killer)
+				.string(r#"{"text":"died"}"#)
+		};
+		assert_eq!(
+			clientbound(0x2D, dead(SERVER, OTHER)),
+			dead(CLIENT, OTHER).0
+		);
+		assert_eq!(
+			clientbound(0x2D, dead(OTHER, SERVER)),
+			dead(OTHER, CLIENT).0
+		);
+		let end = |entity| Packet::default().varint(1).varint(20).int(entity);
+		assert_eq!(clientbound(0x2D, end(SERVER)), end(CLIENT).0);
+		let enter = Packet::default().varint(0);
+		assert_eq!(clientbound(0x2D, enter), Packet::default().varint(0).0);
+	}
+
+	#[test]
+	fn serverbound_use_entity() {
+		let use_entity = |target| Packet::default().varint(target).varint(1).bytes(&[0x55]);
+		let map = map();
+		assert!(map.rewrites_serverbound(0x0A));
+		assert_eq!(
+			map.rewrite_serverbound(0x0A, &use_entity(CLIENT).0)
+				.unwrap(),
+			use_entity(SERVER).0
+		);
+		assert_eq!(
+			map.rewrite_serverbound(0x0A, &use_entity(OTHER).0).unwrap(),
+			use_entity(OTHER).0
+		);
+	}
+
+	#[test]
+	fn entity_metadata_bad_lengths_are_errors() {
+		// Строка длиннее пакета
+		let packet = Packet::default()
+			.varint(OTHER)
+			.bytes(&[3])
+			.varint(3)
+			.varint(i32::MAX)
+			.bytes(b"name");
+		assert!(map().rewrite_clientbound(0x3C, &packet.0).is_err());
+		let packet = Packet::default()
+			.varint(OTHER)
+			.bytes(&[3])
+			.varint(3)
+			// VarInt -1
+			.bytes(&[0xff, 0xff, 0xff, 0xff, 0x0f]);
+		assert!(map().rewrite_clientbound(0x3C, &packet.0).is_err());
+		// Массив в NBT предмета
+		for len in &[i32::MAX, -1] {
+			let packet = Packet::default()
+				.varint(OTHER)
+				.bytes(&[2])
+				.varint(5)
+				.short(261)
+				.bytes(&[1, 0, 0])
+				.bytes(&[11])
+				.nbt_string("")
+				.int(*len)
+				.int(7);
+			assert!(map().rewrite_clientbound(0x3C, &packet.0).is_err());
+		}
+	}
+
+	#[test]
+	fn same_ids_need_no_rewrite() {
+		let map = EntityMap {
+			client: SERVER,
+			server: SERVER,
+		};
+		assert!(!map.rewrites_clientbound(0x00));
+		assert!(!map.rewrites_serverbound(0x0A));
+	}
+}
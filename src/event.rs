@@ -0,0 +1,192 @@
+use std::{
+	any::{Any, TypeId},
+	collections::HashMap,
+	net::SocketAddr,
+	sync::{Arc, RwLock},
+};
+
+use futures::future::BoxFuture;
+
+use crate::{plugin::TargetServer, server::ConnectedPlayer, LoggedInInfo};
+
+pub trait Event: Send + 'static {}
+
+/// Обработчики с меньшим приоритетом вызываются раньше, так что последнее слово за обработчиками с большим
+pub mod priority {
+	pub const EARLY: i32 = -100;
+	pub const NORMAL: i32 = 0;
+	pub const LATE: i32 = 100;
+}
+
+type Handler<E> = Box<dyn for<'e> Fn(&'e mut E) -> BoxFuture<'e, ()> + Send + Sync>;
+/// Приоритет и `Arc<Handler<E>>`, тип события восстанавливается по ключу
+type AnyHandler = (i32, Arc<dyn Any + Send + Sync>);
+
+#[derive(Default)]
+pub struct EventBus {
+	handlers: RwLock<HashMap<TypeId, Vec<AnyHandler>>>,
+}
+impl EventBus {
+	/// Обработчик с одинаковым приоритетом вызывается после уже зарегистрированных
+	pub fn register<E, F>(&self, priority: i32, handler: F)
+	where
+		E: Event,
+		F: for<'e> Fn(&'e mut E) -> BoxFuture<'e, ()> + Send + Sync + 'static,
+	{
+		let handler: Arc<Handler<E>> = Arc::new(Box::new(handler));
+		let mut handlers = self.handlers.write().unwrap();
+		let handlers = handlers.entry(TypeId::of::<E>()).or_default();
+		let index = handlers.partition_point(|(p, _)| *p <= priority);
+		handlers.insert(index, (priority, handler));
+	}
+
+	/// Прогоняет событие через все обработчики, возвращает результат их работы
+	pub async fn fire<E: Event>(&self, mut event: E) -> E {
+		let handlers = match self.handlers.read().unwrap().get(&TypeId::of::<E>()) {
+			Some(handlers) => handlers
+				.iter()
+				.map(|(_, handler)| handler.clone().downcast::<Handler<E>>().unwrap())
+				.collect::<Vec<_>>(),
+			None => return event,
+		};
+		for handler in handlers {
+			handler(&mut event).await;
+		}
+		event
+	}
+}
+
+/// Клиент прислал LoginStart, авторизация ещё не проводилась
+#[derive(Debug)]
+pub struct PreLoginEvent {
+	pub username: String,
+	pub protocol: i32,
+	pub handshake_address: String,
+	pub client_addr: SocketAddr,
+	/// Причина отключения, если событие отменено
+	pub cancel_reason: Option<String>,
+}
+impl Event for PreLoginEvent {}
+
+/// Игрок прошёл авторизацию, но ещё не зарегистрирован на прокси
+#[derive(Debug)]
+pub struct LoginEvent {
+	pub info: LoggedInInfo,
+	pub client_addr: SocketAddr,
+	/// Причина отключения, если событие отменено
+	pub cancel_reason: Option<String>,
+}
+impl Event for LoginEvent {}
+
+/// Игрок прошёл лимбо и зарегистрирован на прокси, подключение к серверу ещё не начато
+pub struct PostLoginEvent {
+	pub player: Arc<ConnectedPlayer>,
+}
+impl Event for PostLoginEvent {}
+
+/// Перед подключением к очередному серверу, сервер можно подменить
+pub struct ServerPreConnectEvent {
+	pub player: Arc<ConnectedPlayer>,
+	pub target: TargetServer,
+	/// Сервер будет пропущен
+	pub cancelled: bool,
+}
+impl Event for ServerPreConnectEvent {}
+
+/// Сервер принял игрока
+pub struct ServerConnectedEvent {
+	pub player: Arc<ConnectedPlayer>,
+	pub server: TargetServer,
+}
+impl Event for ServerConnectedEvent {}
+
+/// Сервер кикнул игрока, либо закрыл соединение
+pub struct ServerKickEvent {
+	pub player: Arc<ConnectedPlayer>,
+	pub server: TargetServer,
+	/// json компонент, будет показан игроку
+	pub reason: String,
+	/// Перекинуть игрока сюда вместо обычного поиска запасного сервера
+	pub redirect: Option<TargetServer>,
+	/// Сразу отключить игрока, не пытаясь найти запасной сервер
+	pub cancelled: bool,
+}
+impl Event for ServerKickEvent {}
+
+/// Сообщение или команда от игрока, до обработки командами прокси
+pub struct ChatEvent {
+	pub player: Arc<ConnectedPlayer>,
+	pub message: String,
+	/// Сообщение не дойдёт ни до прокси, ни до сервера
+	pub cancelled: bool,
+}
+impl Event for ChatEvent {}
+
+/// Сессия игрока завершилась, вызывается ровно один раз
+pub struct DisconnectEvent {
+	pub player: Arc<ConnectedPlayer>,
+	/// json компонент, если игрока отключила прокси
+	pub reason: Option<String>,
+}
+impl Event for DisconnectEvent {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use futures::future::FutureExt;
+
+	#[derive(Default)]
+	struct TestEvent {
+		calls: Vec<&'static str>,
+		cancelled: bool,
+	}
+	impl Event for TestEvent {}
+
+	fn log(bus: &EventBus, priority: i32, name: &'static str) {
+		bus.register(priority, move |event: &mut TestEvent| {
+			async move { event.calls.push(name) }.boxed()
+		});
+	}
+
+	#[tokio::test]
+	async fn priority_order() {
+		let bus = EventBus::default();
+		log(&bus, priority::LATE, "late");
+		log(&bus, priority::NORMAL, "normal 1");
+		log(&bus, priority::EARLY, "early");
+		log(&bus, priority::NORMAL, "normal 2");
+		log(&bus, priority::NORMAL, "normal 3");
+		let event = bus.fire(TestEvent::default()).await;
+		assert_eq!(
+			event.calls,
+			["early", "normal 1", "normal 2", "normal 3", "late"]
+		);
+	}
+
+	#[tokio::test]
+	async fn later_handlers_see_and_override_cancellation() {
+		let bus = EventBus::default();
+		bus.register(priority::LATE, |event: &mut TestEvent| {
+			async move {
+				assert!(event.cancelled);
+				event.cancelled = false;
+			}
+			.boxed()
+		});
+		bus.register(priority::EARLY, |event: &mut TestEvent| {
+			async move { event.cancelled = true }.boxed()
+		});
+		assert!(!bus.fire(TestEvent::default()).await.cancelled);
+	}
+
+	#[tokio::test]
+	async fn handlers_are_per_event_type() {
+		struct Other;
+		impl Event for Other {}
+		let bus = EventBus::default();
+		assert!(bus.fire(TestEvent::default()).await.calls.is_empty());
+		log(&bus, priority::NORMAL, "test");
+		bus.fire(Other).await;
+		assert_eq!(bus.fire(TestEvent::default()).await.calls, ["test"]);
+	}
+}
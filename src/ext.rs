@@ -1,4 +1,4 @@
-use crate::protocol::Packet;
+use crate::protocol::{Packet, PacketData};
 use async_trait::async_trait;
 use compress::zlib::Decoder;
 use std::io::{BufReader, Cursor, Read, Write};
@@ -59,6 +59,7 @@ pub enum MaybeCompressed<'t> {
 	PartiallyDecompressed {
 		packet_id: i32,
 		full_size: usize,
+		compressed: &'t [u8],
 		decoder: compress::zlib::Decoder<Cursor<&'t [u8]>>,
 	},
 	Plain {
@@ -88,12 +89,14 @@ impl<'t> MaybeCompressed<'t> {
 				let mut decoder = Decoder::new(cursor);
 				let packet_id = Varint21::read(&mut decoder)?;
 
+				// full_size остаётся полным размером, т.к. используется при пересылке как есть
 				*self = MaybeCompressed::PartiallyDecompressed {
 					packet_id: packet_id.ans,
 					decoder,
-					full_size: full_size - packet_id.size as usize,
+					compressed,
+					full_size,
 				};
-				todo!()
+				Ok(())
 			}
 			_ => Ok(()),
 		}
@@ -113,7 +116,9 @@ impl<'t> MaybeCompressed<'t> {
 				T::read(&mut Cursor::new(decompressed))
 			}
 			MaybeCompressed::Compressed { compressed, .. } => {
-				T::read(&mut BufReader::new(Decoder::new(Cursor::new(compressed))))
+				let mut reader = BufReader::new(Decoder::new(Cursor::new(compressed)));
+				reader.read_varint()?;
+				T::read(&mut reader)
 			}
 			MaybeCompressed::PartiallyDecompressed { decoder, .. } => {
 				T::read(&mut BufReader::new(decoder))
@@ -121,6 +126,36 @@ impl<'t> MaybeCompressed<'t> {
 			MaybeCompressed::Plain { mut data, .. } => T::read(&mut data),
 		}
 	}
+	/// Распакованное тело пакета без ID
+	pub fn into_data(self) -> io::Result<Vec<u8>> {
+		let mut out = Vec::new();
+		match self {
+			MaybeCompressed::Decompressed { decompressed, .. } => return Ok(decompressed),
+			MaybeCompressed::Compressed { compressed, .. } => {
+				let mut decoder = Decoder::new(Cursor::new(compressed));
+				decoder.read_varint()?;
+				decoder.read_to_end(&mut out)?;
+			}
+			MaybeCompressed::PartiallyDecompressed { mut decoder, .. } => {
+				decoder.read_to_end(&mut out)?;
+			}
+			MaybeCompressed::Plain { data, .. } => out.extend_from_slice(data),
+		}
+		Ok(out)
+	}
+	/// Читает начало пакета, не поглощая его, чтобы затем переслать пакет как есть
+	pub fn peek<T: PacketData>(&self) -> io::Result<T> {
+		match self {
+			MaybeCompressed::Decompressed { decompressed, .. } => T::read(&mut &decompressed[..]),
+			MaybeCompressed::Compressed { compressed, .. }
+			| MaybeCompressed::PartiallyDecompressed { compressed, .. } => {
+				let mut reader = BufReader::new(Decoder::new(Cursor::new(*compressed)));
+				reader.read_varint()?;
+				T::read(&mut reader)
+			}
+			MaybeCompressed::Plain { data, .. } => T::read(&mut &data[..]),
+		}
+	}
 	pub async fn write<W: AsyncWrite + Unpin + Send>(
 		self,
 		compression_threshold: Option<i32>,
@@ -156,13 +191,14 @@ impl<'t> MaybeCompressed<'t> {
 				buf.write_all(compressed).await?;
 			}
 			MaybeCompressed::PartiallyDecompressed {
-				full_size, decoder, ..
+				full_size,
+				compressed,
+				..
 			} if compression_threshold
 				.map(|compression_threshold| compression_threshold as usize <= full_size)
 				.unwrap_or(false) =>
 			{
 				let size_size = varint_size(full_size as i32);
-				let compressed = decoder.unwrap().into_inner();
 				buf.write_varint(compressed.len() as i32 + size_size as i32)
 					.await?;
 				buf.write_varint(full_size as i32).await?;
@@ -340,12 +376,23 @@ pub trait MinecraftAsyncWriteExt: AsyncWrite + Unpin {
 			Ok(())
 		}
 	}
+	async fn write_packet_data(
+		&mut self,
+		packet_id: i32,
+		compression: Option<i32>,
+		data: &[u8],
+	) -> io::Result<()> {
+		self.write_packet_fn(packet_id, compression, |w| Write::write_all(w, data))
+			.await
+	}
 	async fn write_bytes_async(&mut self, buf: &[u8]) -> io::Result<()> {
 		self.write_varint(buf.len() as i32).await?;
 		self.write_all(&buf).await?;
 		Ok(())
 	}
-	async fn write_varint(&mut self, mut value: i32) -> io::Result<()> {
+	async fn write_varint(&mut self, value: i32) -> io::Result<()> {
+		// Отрицательные числа пишутся как беззнаковые, всегда 5 байт
+		let mut value = value as u32;
 		loop {
 			let mut temp = value as u8 & 0b01111111;
 			value >>= 7;
@@ -362,7 +409,8 @@ pub trait MinecraftAsyncWriteExt: AsyncWrite + Unpin {
 }
 impl<T> MinecraftAsyncWriteExt for T where T: AsyncWrite + Unpin {}
 
-pub fn varint_size(mut value: i32) -> usize {
+pub fn varint_size(value: i32) -> usize {
+	let mut value = value as u32;
 	let mut size = 0;
 	loop {
 		value >>= 7;
@@ -375,7 +423,8 @@ pub fn varint_size(mut value: i32) -> usize {
 }
 
 pub trait MinecraftWriteExt: Write {
-	fn write_varint(&mut self, mut value: i32) -> io::Result<()> {
+	fn write_varint(&mut self, value: i32) -> io::Result<()> {
+		let mut value = value as u32;
 		loop {
 			let mut temp = value as u8 & 0b01111111;
 			value >>= 7;
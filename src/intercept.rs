@@ -0,0 +1,132 @@
+use std::{
+	collections::HashMap,
+	sync::{Arc, RwLock},
+};
+
+use futures::future::BoxFuture;
+use tokio::io;
+
+use crate::{
+	protocol::{Packet, PacketData},
+	server::ConnectedPlayer,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Direction {
+	Serverbound,
+	Clientbound,
+}
+
+/// Пакет, на ID которого зарегистрирован хотя бы один обработчик.
+/// Клиентские пакеты видны в том виде, в каком их прислал клиент, серверные - в каком прислал сервер,
+/// подмена ID сущностей выполняется уже после обработчиков
+pub struct InterceptedPacket {
+	pub player: Arc<ConnectedPlayer>,
+	pub direction: Direction,
+	pub id: i32,
+	/// Распакованное тело пакета без ID
+	pub data: Vec<u8>,
+	/// Пакет не будет отправлен, добавленные пакеты отправляются всё равно
+	pub dropped: bool,
+	/// Пакеты, отправляемые в том же направлении сразу после этого.
+	/// В обратном направлении пакеты шлются через `player.handle()`
+	pub injected: Vec<(i32, Vec<u8>)>,
+}
+impl InterceptedPacket {
+	pub(crate) fn new(
+		player: Arc<ConnectedPlayer>,
+		direction: Direction,
+		id: i32,
+		data: Vec<u8>,
+	) -> Self {
+		Self {
+			player,
+			direction,
+			id,
+			data,
+			dropped: false,
+			injected: Vec::new(),
+		}
+	}
+	pub fn decode<T: PacketData>(&self) -> io::Result<T> {
+		T::read(&mut &self.data[..])
+	}
+	pub fn replace<T: Packet>(&mut self, packet: &T) -> io::Result<()> {
+		self.id = T::ID;
+		self.data.clear();
+		packet.write(&mut self.data)
+	}
+	pub fn inject<T: Packet>(&mut self, packet: &T) -> io::Result<()> {
+		let mut data = Vec::new();
+		packet.write(&mut data)?;
+		self.injected.push((T::ID, data));
+		Ok(())
+	}
+	/// Пакеты, которые нужно отправить дальше, по порядку
+	pub(crate) fn into_packets(self) -> impl Iterator<Item = (i32, Vec<u8>)> {
+		let packet = if self.dropped {
+			None
+		} else {
+			Some((self.id, self.data))
+		};
+		packet.into_iter().chain(self.injected)
+	}
+}
+
+type Handler = Arc<dyn for<'p> Fn(&'p mut InterceptedPacket) -> BoxFuture<'p, ()> + Send + Sync>;
+/// (направление, ID пакета) -> обработчики с приоритетами, по возрастанию приоритета
+type Handlers = HashMap<(Direction, i32), Vec<(i32, Handler)>>;
+
+/// Обработчики пакетов по направлению и ID.
+/// Пакеты без обработчиков пересылаются как есть, не распаковываясь.
+/// Пакеты, которые разбирает сама прокси (чат, Tab-Complete, JoinGame, Disconnect), сюда не попадают,
+/// для них есть события
+#[derive(Default)]
+pub struct PacketHooks {
+	handlers: RwLock<Handlers>,
+}
+impl PacketHooks {
+	/// Порядок вызова по приоритету такой же, как у `EventBus`
+	pub fn register<F>(&self, direction: Direction, packet_id: i32, priority: i32, handler: F)
+	where
+		F: for<'p> Fn(&'p mut InterceptedPacket) -> BoxFuture<'p, ()> + Send + Sync + 'static,
+	{
+		let mut handlers = self.handlers.write().unwrap();
+		let handlers = handlers.entry((direction, packet_id)).or_default();
+		let index = handlers.partition_point(|(p, _)| *p <= priority);
+		handlers.insert(index, (priority, Arc::new(handler)));
+	}
+	pub fn register_packet<T, F>(&self, direction: Direction, priority: i32, handler: F)
+	where
+		T: Packet,
+		F: for<'p> Fn(&'p mut InterceptedPacket) -> BoxFuture<'p, ()> + Send + Sync + 'static,
+	{
+		self.register(direction, T::ID, priority, handler)
+	}
+
+	pub fn intercepts(&self, direction: Direction, packet_id: i32) -> bool {
+		self.handlers
+			.read()
+			.unwrap()
+			.contains_key(&(direction, packet_id))
+	}
+
+	pub async fn handle(&self, mut packet: InterceptedPacket) -> InterceptedPacket {
+		let handlers = match self
+			.handlers
+			.read()
+			.unwrap()
+			.get(&(packet.direction, packet.id))
+		{
+			Some(handlers) => handlers
+				.iter()
+				.map(|(_, handler)| handler.clone())
+				.collect::<Vec<_>>(),
+			None => return packet,
+		};
+		for handler in handlers {
+			handler(&mut packet).await;
+		}
+		packet
+	}
+}
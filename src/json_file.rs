@@ -0,0 +1,92 @@
+use std::{
+	fs, io,
+	path::PathBuf,
+	sync::{RwLock, RwLockReadGuard},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Данные в json файле, отсутствующий файл - значение по умолчанию.
+/// Без файла (`Default`) изменения живут только в памяти
+#[derive(Default)]
+pub struct JsonFile<T> {
+	path: Option<PathBuf>,
+	data: RwLock<T>,
+}
+impl<T: Serialize + DeserializeOwned + Default> JsonFile<T> {
+	/// Если файла нет, он будет создан при первом изменении
+	pub fn load<E>(path: impl Into<PathBuf>) -> Result<Self, E>
+	where
+		E: From<io::Error> + From<serde_json::Error>,
+	{
+		let path = path.into();
+		let data = RwLock::new(Self::read_file::<E>(&path)?);
+		Ok(Self {
+			path: Some(path),
+			data,
+		})
+	}
+	fn read_file<E>(path: &PathBuf) -> Result<T, E>
+	where
+		E: From<io::Error> + From<serde_json::Error>,
+	{
+		match fs::read_to_string(path) {
+			Ok(data) => Ok(serde_json::from_str(&data)?),
+			Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+			Err(e) => Err(e.into()),
+		}
+	}
+	pub fn reload<E>(&self) -> Result<(), E>
+	where
+		E: From<io::Error> + From<serde_json::Error>,
+	{
+		if let Some(path) = &self.path {
+			*self.data.write().unwrap() = Self::read_file::<E>(path)?;
+		}
+		Ok(())
+	}
+
+	pub fn read(&self) -> RwLockReadGuard<'_, T> {
+		self.data.read().unwrap()
+	}
+
+	/// Изменяет данные и сразу сохраняет их, файл пишется под той же блокировкой
+	pub fn update<R, E>(&self, f: impl FnOnce(&mut T) -> R) -> Result<R, E>
+	where
+		E: From<io::Error> + From<serde_json::Error>,
+	{
+		let mut data = self.data.write().unwrap();
+		let result = f(&mut data);
+		if let Some(path) = &self.path {
+			fs::write(path, serde_json::to_string_pretty(&*data)?)?;
+		}
+		Ok(result)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::HashMap;
+
+	#[test]
+	fn missing_file_then_update_and_reload() {
+		let path = std::env::temp_dir().join(format!("json-file-test-{}.json", std::process::id()));
+		let _ = fs::remove_file(&path);
+		let file = JsonFile::<HashMap<String, u32>>::load::<io::Error>(&path).unwrap();
+		assert!(file.read().is_empty());
+		file.update::<_, io::Error>(|data| data.insert("a".to_owned(), 1))
+			.unwrap();
+
+		let loaded = JsonFile::<HashMap<String, u32>>::load::<io::Error>(&path).unwrap();
+		assert_eq!(loaded.read().get("a"), Some(&1));
+		fs::write(&path, r#"{"b": 2}"#).unwrap();
+		loaded.reload::<io::Error>().unwrap();
+		assert_eq!(loaded.read().get("b"), Some(&2));
+		assert_eq!(loaded.read().get("a"), None);
+
+		fs::write(&path, "not json").unwrap();
+		assert!(loaded.reload::<io::Error>().is_err());
+		fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,185 @@
+use std::{
+	sync::Arc,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use thiserror::Error;
+use tokio::{io, net::TcpStream, select, sync::mpsc, time::interval};
+
+use crate::{
+	chat,
+	client_state::ClientState,
+	ext::*,
+	protocol::{
+		login::{LoginSuccess, SetCompression},
+		play::{ChatRequest, ChatResponse, JoinGame, KeepAlive, PlayerPositionAndLook},
+		Packet,
+	},
+	server::ConnectedPlayer,
+	session::SessionCommand,
+	LoggedInInfo, THRESHOLD,
+};
+
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+/// Станет ID игрока у клиента на всё соединение. 0 в метаданных означает "никого",
+/// а серверы раздают ID с малых чисел, так что берём то, что им не встретится
+const LIMBO_ENTITY_ID: i32 = i32::MAX;
+
+#[derive(Debug, Error)]
+pub enum LimboError {
+	#[error("io error: {0}")]
+	Io(#[from] io::Error),
+	#[error("client closed the connection")]
+	Closed,
+	/// Отключить игрока, причина - json компонент
+	#[error("disconnected: {0}")]
+	Disconnect(String),
+}
+impl LimboError {
+	pub fn disconnect(reason: &str) -> Self {
+		LimboError::Disconnect(chat::text(reason))
+	}
+}
+impl From<CompressedError> for LimboError {
+	fn from(e: CompressedError) -> Self {
+		LimboError::Io(io::Error::new(io::ErrorKind::InvalidData, e))
+	}
+}
+
+/// Игрок уже авторизован, но ещё не подключен ни к одному серверу, с ним общается сама прокси.
+/// Клиент попадает в пустой мир только при первом сообщении, до этого он остаётся на экране входа
+pub struct Limbo<'s> {
+	user: &'s mut TcpStream,
+	client: &'s mut ClientState,
+	commands: &'s mut mpsc::UnboundedReceiver<SessionCommand>,
+	info: &'s LoggedInInfo,
+	player: Arc<ConnectedPlayer>,
+	entered: bool,
+	buf: Vec<u8>,
+}
+impl<'s> Limbo<'s> {
+	pub(crate) fn new(
+		user: &'s mut TcpStream,
+		client: &'s mut ClientState,
+		commands: &'s mut mpsc::UnboundedReceiver<SessionCommand>,
+		info: &'s LoggedInInfo,
+		player: Arc<ConnectedPlayer>,
+	) -> Self {
+		Self {
+			user,
+			client,
+			commands,
+			info,
+			player,
+			entered: false,
+			buf: Vec::new(),
+		}
+	}
+
+	pub fn info(&self) -> &LoggedInInfo {
+		self.info
+	}
+	pub fn player(&self) -> &Arc<ConnectedPlayer> {
+		&self.player
+	}
+	/// Клиенту уже отправлены LoginSuccess и JoinGame
+	pub fn entered(&self) -> bool {
+		self.entered
+	}
+
+	async fn enter(&mut self) -> io::Result<()> {
+		if self.entered {
+			return Ok(());
+		}
+		self.entered = true;
+		self.user
+			.write_packet(
+				None,
+				&SetCompression {
+					threshold: THRESHOLD.into(),
+				},
+			)
+			.await?;
+		self.user
+			.write_packet(
+				Some(THRESHOLD),
+				&LoginSuccess {
+					username: self.info.username.clone(),
+					uuid: self.info.uuid.clone(),
+				},
+			)
+			.await?;
+		// Пустой Энд в режиме приключения, первый JoinGame от сервера станет респавном
+		let join = JoinGame {
+			entity_id: LIMBO_ENTITY_ID,
+			game_mode: 2,
+			dimension: 1,
+			difficulty: 0,
+			max_players: 1,
+			level_type: "default".to_owned(),
+			reduced_debug_info: false,
+		};
+		self.client.join(join, Some(THRESHOLD), self.user).await?;
+		self.user
+			.write_packet(
+				Some(THRESHOLD),
+				&PlayerPositionAndLook {
+					x: 0.0,
+					y: 64.0,
+					z: 0.0,
+					yaw: 0.0,
+					pitch: 0.0,
+					flags: 0,
+					teleport_id: 0.into(),
+				},
+			)
+			.await
+	}
+
+	pub async fn message(&mut self, message: &str) -> io::Result<()> {
+		self.enter().await?;
+		self.user
+			.write_packet(
+				Some(THRESHOLD),
+				&ChatResponse {
+					message: chat::text(message),
+					position: 0,
+				},
+			)
+			.await
+	}
+
+	/// Ждёт сообщение или команду из чата, остальные пакеты клиента отбрасываются
+	pub async fn next_message(&mut self) -> Result<String, LimboError> {
+		self.enter().await?;
+		let mut keep_alive = interval(KEEP_ALIVE_INTERVAL);
+		let mut peek_buf = [0];
+		loop {
+			select! {
+				_ = keep_alive.tick() => {
+					let random_id = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+					self.user.write_packet(Some(THRESHOLD), &KeepAlive { random_id }).await?;
+				}
+				Some(command) = self.commands.recv() => {
+					match command {
+						SessionCommand::Kick(reason) => return Err(LimboError::Disconnect(reason)),
+						SessionCommand::Chat(message) => {
+							self.user.write_packet(Some(THRESHOLD), &ChatResponse { message, position: 0 }).await?;
+						}
+						// Серверов ещё нет, остальное некуда применить
+						_ => {}
+					}
+				}
+				read = self.user.peek(&mut peek_buf) => {
+					if read? == 0 {
+						return Err(LimboError::Closed);
+					}
+					let mut packet = self.user.read_packet(Some(THRESHOLD), &mut self.buf).await?;
+					if packet.id()? == ChatRequest::ID {
+						return Ok(packet.decode::<ChatRequest>()?.message);
+					}
+				}
+			}
+		}
+	}
+}
@@ -1,38 +1,64 @@
+mod chat;
+mod client_state;
+mod command;
+mod entity_map;
+mod event;
 mod ext;
+mod intercept;
+mod json_file;
+mod limbo;
+mod permissions;
 mod plugin;
 pub mod plugins;
 mod protocol;
+mod proxy_protocol;
+mod server;
+mod session;
 
+use async_trait::async_trait;
+use event::{LoginEvent, PreLoginEvent, ServerPreConnectEvent};
 use ext::*;
 use log::warn;
-use plugin::{Plugin, TargetServer};
-use plugins::auth::{AuthError, AuthPlugin};
+use permissions::Permissions;
+use plugin::{Plugin, RouteContext, RouteReason, RouteResult, TargetServer};
+use plugins::auth::{AuthError, AuthPlugin, ProfileProperty};
 use protocol::{
 	handshake::Handshake,
 	login::{
 		Disconnect, EncryptionRequest, EncryptionResponse, LoginStart, LoginSuccess, SetCompression,
 	},
-	play::{ChatRequest, ChatResponse},
 	status::{Ping, Pong, StatusRequest, StatusResponse},
 	Packet, State,
 };
 use quick_error::quick_error;
+use server::{ConnectedPlayer, DuplicateLoginPolicy, Forwarding, ProxyServer};
+use session::Session;
+use std::time::Duration;
+use std::{net::SocketAddr, sync::Arc};
 use thiserror::Error;
-use tokio::{io, net::lookup_host};
-use tokio::{
-	net::{TcpListener, TcpStream},
-	select,
-};
+use tokio::io;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
 
-use crate::plugins::auth::{MojangAuthPlugin, OfflineAuthPlugin};
+use crate::plugins::{
+	access::BanList,
+	auth::{Filtered, MojangAuthPlugin, OfflineAuthPlugin},
+	fallback::FallbackPlugin,
+	password::PasswordLogin,
+};
 
 const THRESHOLD: i32 = 256;
+const SWITCH_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Debug)]
 pub struct LoggedInInfo {
-	username: String,
-	uuid: String,
-	protocol: i32,
+	pub username: String,
+	pub uuid: String,
+	pub protocol: i32,
+	/// Адрес, который клиент указал в хендшейке
+	pub handshake_address: String,
+	/// Подписанные свойства профиля, пусто в оффлайн режиме
+	pub properties: Vec<ProfileProperty>,
 }
 #[derive(Debug, Error)]
 pub enum SocketLoginError {
@@ -46,18 +72,66 @@ pub enum SocketLoginError {
 	AuthPluginDidntRequestedEncryption,
 	#[error("auth error: {0}")]
 	AuthError(#[from] AuthError),
+	#[error("login was cancelled by plugin: {0}")]
+	Cancelled(String),
+	#[error("invalid username: {0:?}")]
+	InvalidUsername(String),
+	#[error("login took too long")]
+	TimedOut,
+}
+impl SocketLoginError {
+	/// Причина для показа игроку, json компонент. None - клиент не в состоянии входа,
+	/// либо соединение уже не годится для отправки
+	pub fn disconnect_reason(&self) -> Option<String> {
+		Some(match self {
+			SocketLoginError::Io(_)
+			| SocketLoginError::IncorrectStateIdCombo(State::Handshaking, _)
+			| SocketLoginError::IncorrectStateIdCombo(State::Status, _) => return None,
+			SocketLoginError::IncorrectStateIdCombo(..)
+			| SocketLoginError::MissingLoginStart
+			| SocketLoginError::AuthPluginDidntRequestedEncryption => {
+				chat::internal_error(&self.to_string())
+			}
+			SocketLoginError::AuthError(e) => match e {
+				AuthError::NotAuthenticated => chat::translate(
+					"disconnect.loginFailedInfo",
+					&[chat::translate(
+						"disconnect.loginFailedInfo.invalidSession",
+						&[],
+					)],
+				),
+				AuthError::Denied(reason) => chat::text(reason),
+				// Сервер сессий или API профилей не ответили
+				AuthError::Other(_) => chat::translate(
+					"disconnect.loginFailedInfo",
+					&[chat::translate(
+						"disconnect.loginFailedInfo.serversUnavailable",
+						&[],
+					)],
+				),
+				e => chat::internal_error(&e.to_string()),
+			},
+			SocketLoginError::Cancelled(reason) => chat::text(reason),
+			SocketLoginError::InvalidUsername(_) => chat::text("Invalid username"),
+			SocketLoginError::TimedOut => chat::translate("multiplayer.disconnect.slow_login", &[]),
+		})
+	}
 }
 
 /// Проводит авторизацию юзера/выходит при ошибке/запросе статуса
+/// При ошибке клиенту ещё ничего не отправлено, см. [`SocketLoginError::disconnect_reason`]
 async fn handle_socket_login<A: AuthPlugin>(
-	mut stream: TcpStream,
+	stream: &mut TcpStream,
+	client_addr: SocketAddr,
+	proxy: &ProxyServer,
 	plugin: &impl Plugin,
 	auth_plugin: &A,
-) -> Result<(TcpStream, LoggedInInfo), SocketLoginError> {
+) -> Result<LoggedInInfo, SocketLoginError> {
 	let mut state = State::Handshaking;
 	let mut protocol = None::<i32>;
+	let mut handshake_address = String::new();
 	let mut auth_data = None::<A::AuthData>;
-	loop {
+	let success = loop {
 		let mut initial_buffer = Vec::new();
 		let mut data = stream.read_packet(None, &mut initial_buffer).await?;
 		match (
@@ -70,6 +144,7 @@ async fn handle_socket_login<A: AuthPlugin>(
 				println!("Handshake: {:?}", packet);
 				state = packet.next_state;
 				protocol = Some(packet.protocol.0);
+				handshake_address = packet.address;
 			}
 			(State::Status, StatusResponse::ID) => {
 				let req = data.decode::<StatusRequest>()?;
@@ -118,22 +193,33 @@ async fn handle_socket_login<A: AuthPlugin>(
 			}
 			(State::Login, LoginStart::ID) => {
 				let req = data.decode::<LoginStart>()?;
+				// Имя дальше идёт в оффлайн UUID и на серверы
+				if !proxy.username_rules().is_valid(&req.name) {
+					return Err(SocketLoginError::InvalidUsername(req.name));
+				}
+				let event = proxy
+					.events()
+					.fire(PreLoginEvent {
+						username: req.name.clone(),
+						protocol: protocol.unwrap(),
+						handshake_address: handshake_address.clone(),
+						client_addr,
+						cancel_reason: None,
+					})
+					.await;
+				if let Some(reason) = event.cancel_reason {
+					return Err(SocketLoginError::Cancelled(reason));
+				}
 
-				match auth_plugin.encryption_start(req.name.clone()) {
-					plugins::auth::EncryptionStartResult::BeginEncryption(request, data) => {
+				match auth_plugin.encryption_start(req.name.clone()).await {
+					Ok(plugins::auth::EncryptionStartResult::BeginEncryption(request, data)) => {
 						auth_data = Some(data);
 						stream.write_packet(None, &request).await?;
 					}
-					plugins::auth::EncryptionStartResult::Skip(d) => {
-						break Ok((
-							stream,
-							LoggedInInfo {
-								username: d.username,
-								uuid: d.uuid,
-								protocol: protocol.unwrap(),
-							},
-						));
+					Ok(plugins::auth::EncryptionStartResult::Skip(success)) => {
+						break auth_plugin.verify(success).await;
 					}
+					Err(e) => break Err(e),
 				}
 			}
 			(State::Login, EncryptionResponse::ID) => {
@@ -141,21 +227,42 @@ async fn handle_socket_login<A: AuthPlugin>(
 					.take()
 					.ok_or(SocketLoginError::AuthPluginDidntRequestedEncryption)?;
 				let res = data.decode::<EncryptionResponse>()?;
-				let success = auth_plugin.encryption_response(auth_data, res).await?;
-				break Ok((
-					stream,
-					LoggedInInfo {
-						username: success.username,
-						uuid: success.uuid,
-						protocol: protocol.unwrap(),
-					},
-				));
+				let success = match auth_plugin
+					.encryption_response(auth_data, res, client_addr)
+					.await
+				{
+					Ok(success) => auth_plugin.verify(success).await,
+					Err(e) => Err(e),
+				};
+				break success;
 			}
-			(state, id) => break Err(SocketLoginError::IncorrectStateIdCombo(state, id)),
+			(state, id) => return Err(SocketLoginError::IncorrectStateIdCombo(state, id)),
 		}
+	}?;
+	let info = LoggedInInfo {
+		username: success.username,
+		uuid: success.uuid,
+		protocol: protocol.unwrap(),
+		handshake_address,
+		properties: success.properties,
+	};
+
+	let event = proxy
+		.events()
+		.fire(LoginEvent {
+			info,
+			client_addr,
+			cancel_reason: None,
+		})
+		.await;
+	if let Some(reason) = event.cancel_reason {
+		return Err(SocketLoginError::Cancelled(reason));
 	}
+	Ok(event.info)
+}
+struct ConnectedServerInfo {
+	target: TargetServer,
 }
-struct ConnectedServerInfo;
 #[derive(Debug, Error)]
 pub enum ServerConnectionError {
 	#[error("io error: {0}")]
@@ -170,23 +277,61 @@ pub enum ServerConnectionError {
 	BadLoginSuccess(LoginSuccess),
 	#[error("compression error: {0}")]
 	Compression(#[from] CompressedError),
+	#[error("no target servers to connect to")]
+	NoTargets,
+	#[error("connection was cancelled by plugin")]
+	Cancelled,
+	#[error("server didn't finish login in time")]
+	TimedOut,
+}
+impl ServerConnectionError {
+	/// Причина для показа игроку, json компонент. Причина кика сервером передаётся как есть
+	pub fn into_reason(self) -> String {
+		match self {
+			ServerConnectionError::Disconnect(reason) => reason,
+			e @ ServerConnectionError::NoTargets | e @ ServerConnectionError::Cancelled => {
+				chat::text(&e.to_string())
+			}
+			ServerConnectionError::TimedOut => chat::translate("disconnect.timeout", &[]),
+			e => chat::internal_error(&e.to_string()),
+		}
+	}
 }
 
 /// Открывает соединение с сервером для заданного юзера, проверяет корректность возвращённых данных
 async fn open_server_connection(
 	info: &LoggedInInfo,
+	client_addr: SocketAddr,
+	forwarding: Forwarding,
 	target: TargetServer,
 ) -> Result<(TcpStream, ConnectedServerInfo), ServerConnectionError> {
 	let mut stream = TcpStream::connect(&target.addr).await?;
 	let mut buf = Vec::new();
 	println!("Opening");
 
+	let address = match forwarding {
+		Forwarding::None => target.handshake_address.clone(),
+		// host\0ip\0uuid без дефисов\0свойства профиля в json
+		Forwarding::BungeeCord => {
+			let mut address = format!(
+				"{}\0{}\0{}",
+				target.handshake_address,
+				client_addr.ip(),
+				info.uuid.replace('-', "")
+			);
+			if !info.properties.is_empty() {
+				address.push('\0');
+				address.push_str(&serde_json::to_string(&info.properties).unwrap());
+			}
+			address
+		}
+	};
 	let mut compression = None;
 	stream
 		.write_packet(
 			compression,
 			&Handshake {
-				address: target.handshake_address,
+				address,
 				protocol: info.protocol.into(),
 				port: target.handshake_port,
 				next_state: State::Login,
@@ -223,7 +368,7 @@ async fn open_server_connection(
 				if compression != Some(THRESHOLD) {
 					warn!("Compression settings differ between proxy and server, unnecessary recompressions may be required");
 				}
-				return Ok((stream, ConnectedServerInfo));
+				return Ok((stream, ConnectedServerInfo { target }));
 			}
 			(State::Login, EncryptionRequest::ID) => {
 				break Err(ServerConnectionError::ServerIsInOnlineMode)
@@ -232,75 +377,6 @@ async fn open_server_connection(
 		};
 	}
 }
-struct StreamPair {
-	user: TcpStream,
-	server: TcpStream,
-}
-
-#[derive(PartialEq)]
-enum CommunicateResult {
-	None,
-	AnotherServer(TargetServer),
-}
-
-/// Проводит общение юзера с сервером, успешно выходит после завершения соединения с сервером, падает при падении клиента
-async fn communicate_user_server(
-	streams: StreamPair,
-) -> io::Result<(TcpStream, CommunicateResult)> {
-	let compression = Some(THRESHOLD);
-	let (mut server_read, mut server_write) = streams.server.into_split();
-	let (mut user_read, mut user_write) = streams.user.into_split();
-
-	let mut s_peek_buf = [0];
-	let mut u_peek_buf = [0];
-	let mut packet_buf = Vec::new();
-	let mut action = CommunicateResult::None;
-
-	while action == CommunicateResult::None {
-		// Если есть пакет от сервера - шлём пакет от сервера
-		// Есть от клиента - шлём от клиента
-		// Есть эвент - шлём эвент
-		let action = select! {
-			_ = server_read.peek(&mut s_peek_buf) => {
-				// println!("Server read");
-				let packet = server_read.read_packet(compression, &mut packet_buf).await?;
-				packet.write(compression, &mut user_write).await?;
-			}
-			_ = user_read.peek(&mut u_peek_buf) => {
-				// println!("Client read");
-				let packet = user_read.read_packet(compression, &mut packet_buf).await?;
-				match packet.cheap_id() {
-					Some(ChatRequest::ID) => {
-						let chat = packet.decode::<ChatRequest>()?;
-						println!("Got chat");
-						if chat.message == "/proxy-ping" {
-							user_write.write_packet(compression, &ChatResponse {
-								message: r#"{"text":"Pong"}"#.to_owned(),
-								position: 0,
-							}).await?;
-						}else if  chat.message.starts_with("/proxy-goto "){
-							action = CommunicateResult::AnotherServer(TargetServer {
-								addr: lookup_host(&chat.message["/proxy-goto ".len()..]).await?.next().unwrap(),
-								handshake_address: "test".into(),
-								handshake_port: 25565,
-							});
-						}else {
-							server_write.write_packet(compression, &chat).await?;
-						}
-					}
-					_ => {
-						packet.write(compression, &mut server_write).await?;
-					}
-				}
-			}
-		};
-	}
-
-	let _ = server_read.reunite(server_write).unwrap();
-
-	Ok((user_read.reunite(user_write).unwrap(), action))
-}
-
 quick_error! {
 	#[derive(Debug)]
 	pub enum SocketError {
@@ -310,79 +386,162 @@ quick_error! {
 		Login(err: SocketLoginError) {
 			from()
 		}
-		Server(err: ServerConnectionError) {
-			from()
+	}
+}
+
+/// Спрашивает у плагина, куда отправить игрока, и оставляет доступные ему серверы.
+/// Ошибка - причина отключения
+async fn route_player(
+	plugin: &impl Plugin,
+	proxy: &ProxyServer,
+	info: &LoggedInInfo,
+	client_addr: SocketAddr,
+	reason: RouteReason,
+) -> Result<Vec<TargetServer>, String> {
+	let ctx = RouteContext {
+		proxy,
+		info,
+		client_addr,
+		reason,
+	};
+	let targets = match plugin.route(&ctx).await {
+		Some(RouteResult::Target(target)) => vec![target],
+		Some(RouteResult::Prioritized(targets)) if !targets.is_empty() => targets,
+		Some(RouteResult::Disconnect(reason)) => return Err(reason),
+		_ => match ctx.reason {
+			RouteReason::Command { requested } => vec![requested],
+			_ => return Err("No server is available".to_owned()),
+		},
+	};
+	// Закрытые серверы пропускаются, даже если их выбрал плагин
+	let targets = targets
+		.into_iter()
+		.filter(|target| proxy.can_join(&info.uuid, target))
+		.collect::<Vec<_>>();
+	if targets.is_empty() {
+		return Err("You don't have permission to join this server".to_owned());
+	}
+	Ok(targets)
+}
+
+/// Пробует подключиться к серверам по порядку, возвращает первое удачное соединение
+async fn open_any_server_connection(
+	proxy: &ProxyServer,
+	player: &Arc<ConnectedPlayer>,
+	info: &LoggedInInfo,
+	targets: Vec<TargetServer>,
+) -> Result<(TcpStream, ConnectedServerInfo), ServerConnectionError> {
+	let mut last_error = ServerConnectionError::NoTargets;
+	for target in targets {
+		let event = proxy
+			.events()
+			.fire(ServerPreConnectEvent {
+				player: player.clone(),
+				target,
+				cancelled: false,
+			})
+			.await;
+		if event.cancelled {
+			last_error = ServerConnectionError::Cancelled;
+			continue;
+		}
+		match open_server_connection(info, player.address, proxy.forwarding(), event.target).await {
+			Ok(connection) => return Ok(connection),
+			Err(e) => {
+				println!("Server connection failed: {}", e);
+				last_error = e;
+			}
 		}
 	}
+	Err(last_error)
 }
 
 async fn handle_stream(
-	stream: TcpStream,
+	mut stream: TcpStream,
+	mut client_addr: SocketAddr,
+	proxy: ProxyServer,
 	plugin: &impl Plugin,
 	auth_plugin: &impl AuthPlugin,
 ) -> Result<(), SocketError> {
-	let (mut user, logged_in) = handle_socket_login(stream, plugin, auth_plugin).await?;
-	println!("User logged in: {:?}", logged_in);
-	let mut first_connection = true;
-	let mut target = match plugin.get_initial_target() {
-		Some(target) => target,
-		None => {
-			unreachable!()
-		}
-	};
-	loop {
-		let (mut server, server_info) = open_server_connection(&logged_in, target).await?;
-
-		if first_connection {
-			user.write_packet(
-				None,
-				&SetCompression {
-					threshold: THRESHOLD.into(),
-				},
-			)
-			.await?;
-			user.write_packet(
-				Some(THRESHOLD),
-				&LoginSuccess {
-					username: logged_in.username.clone(),
-					uuid: logged_in.uuid.clone(),
-				},
-			)
-			.await?;
-			first_connection = false;
-		}
-		println!("Server connected");
-		let (new_user, result) = communicate_user_server(StreamPair { user, server })
-			.await
-			.unwrap();
-		user = new_user;
-		match result {
-			CommunicateResult::None => unreachable!(),
-			CommunicateResult::AnotherServer(s) => target = s,
+	if proxy.proxy_protocol() {
+		let header = timeout(
+			proxy.login_timeout(),
+			proxy_protocol::read_header(&mut stream),
+		)
+		.await
+		.map_err(|e| io::Error::new(io::ErrorKind::TimedOut, e))?;
+		if let Some(addr) = header? {
+			client_addr = addr;
 		}
 	}
+	// Включая обмен ключами и запрос к серверу сессий
+	let login = timeout(
+		proxy.login_timeout(),
+		handle_socket_login(&mut stream, client_addr, &proxy, plugin, auth_plugin),
+	);
+	let logged_in = match login.await.unwrap_or(Err(SocketLoginError::TimedOut)) {
+		Ok(logged_in) => logged_in,
+		Err(e) => {
+			if let Some(reason) = e.disconnect_reason() {
+				// Клиент мог уже закрыть соединение, важнее исходная ошибка
+				let _ = stream.write_packet(None, &Disconnect { reason }).await;
+			}
+			return Err(e.into());
+		}
+	};
+	println!("User logged in: {:?}", logged_in);
+	let session = Session::new(stream, logged_in, client_addr, plugin, proxy);
+	let username = session.info().username.clone();
+	let end = session.run().await;
+	println!("Player disconnected: {} ({})", username, end);
+	Ok(())
 }
 
 struct DefaultPlugin;
+#[async_trait]
 impl Plugin for DefaultPlugin {
-	fn get_initial_target(&self) -> Option<TargetServer> {
-		Some(TargetServer {
+	async fn route(&self, ctx: &RouteContext<'_>) -> Option<RouteResult> {
+		if !matches!(ctx.reason, RouteReason::InitialJoin) {
+			return None;
+		}
+		Some(RouteResult::Target(TargetServer {
 			addr: "51.38.192.19:25565".parse().unwrap(),
 			handshake_address: "FunnyMC.ru".to_string(),
 			handshake_port: 25565,
-		})
+		}))
 	}
 }
 
 #[tokio::main(worker_threads = 4)]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
 	let listener = TcpListener::bind("127.0.0.1:25566").await?;
+	let proxy = ProxyServer::new()
+		.with_duplicate_login(DuplicateLoginPolicy::KickExisting)
+		.with_permissions(Permissions::load("permissions.json")?);
+	let plugin = Arc::new((
+		DefaultPlugin,
+		FallbackPlugin::new(vec![TargetServer {
+			addr: "127.0.0.1:25565".parse().unwrap(),
+			handshake_address: "localhost".to_string(),
+			handshake_port: 25565,
+		}])
+		.disable_on("banned"),
+		PasswordLogin::load("passwords.json")?,
+	));
+	plugin.init(&proxy);
+	let auth_plugin = Arc::new(Filtered::new(
+		OfflineAuthPlugin,
+		BanList::load("proxy-bans.json")?,
+	));
 
 	loop {
-		let (stream, _) = listener.accept().await?;
+		let (stream, addr) = listener.accept().await?;
 		println!("Got connection: {:?}", stream);
+		let plugin = plugin.clone();
+		let auth_plugin = auth_plugin.clone();
+		let proxy = proxy.clone();
 		tokio::spawn(async move {
-			if let Err(e) = handle_stream(stream, &DefaultPlugin, &OfflineAuthPlugin).await {
+			if let Err(e) = handle_stream(stream, addr, proxy, &*plugin, &*auth_plugin).await {
 				println!("User error: {:?}", e);
 			};
 		});
@@ -0,0 +1,225 @@
+use std::{
+	collections::{HashMap, HashSet},
+	io,
+	path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::json_file::JsonFile;
+
+/// Группа, которая есть у каждого игрока
+pub const DEFAULT_GROUP: &str = "default";
+
+#[derive(Debug, Error)]
+pub enum PermissionsError {
+	#[error("io error: {0}")]
+	Io(#[from] io::Error),
+	#[error("bad permissions file: {0}")]
+	Json(#[from] serde_json::Error),
+}
+
+/// Узел вида `proxy.command.goto` -> разрешён ли он.
+/// `proxy.command.*` и `*` покрывают все вложенные узлы, более точный узел важнее
+pub type Nodes = HashMap<String, bool>;
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct Group {
+	/// Права родительских групп действуют, если не переопределены в этой
+	#[serde(default)]
+	pub inherits: Vec<String>,
+	#[serde(default)]
+	pub permissions: Nodes,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct PlayerPermissions {
+	#[serde(default)]
+	pub groups: Vec<String>,
+	/// Важнее прав любой из групп
+	#[serde(default)]
+	pub permissions: Nodes,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct PermissionsConfig {
+	#[serde(default)]
+	pub groups: HashMap<String, Group>,
+	/// Ключ - UUID игрока
+	#[serde(default)]
+	pub players: HashMap<String, PlayerPermissions>,
+}
+
+fn lookup(nodes: &Nodes, node: &str) -> Option<bool> {
+	if let Some(value) = nodes.get(node) {
+		return Some(*value);
+	}
+	let mut prefix = node;
+	while let Some(dot) = prefix.rfind('.') {
+		prefix = &prefix[..dot];
+		if let Some(value) = nodes.get(&format!("{}.*", prefix)) {
+			return Some(*value);
+		}
+	}
+	nodes.get("*").copied()
+}
+
+impl PermissionsConfig {
+	/// Сначала права самой группы, затем родителей по порядку
+	fn lookup_group(&self, group: &str, node: &str, visited: &mut HashSet<String>) -> Option<bool> {
+		if !visited.insert(group.to_owned()) {
+			return None;
+		}
+		let group = self.groups.get(group)?;
+		if let Some(value) = lookup(&group.permissions, node) {
+			return Some(value);
+		}
+		group
+			.inherits
+			.iter()
+			.find_map(|parent| self.lookup_group(parent, node, visited))
+	}
+
+	pub fn has_permission(&self, uuid: &str, node: &str) -> bool {
+		let player = self.players.get(uuid);
+		if let Some(value) = player.and_then(|player| lookup(&player.permissions, node)) {
+			return value;
+		}
+		let mut visited = HashSet::new();
+		player
+			.iter()
+			.flat_map(|player| player.groups.iter().map(String::as_str))
+			.chain(Some(DEFAULT_GROUP))
+			.find_map(|group| self.lookup_group(group, node, &mut visited))
+			.unwrap_or(false)
+	}
+}
+
+/// Права игроков, при изменении сохраняются в файл, из которого были загружены
+#[derive(Default)]
+pub struct Permissions {
+	config: JsonFile<PermissionsConfig>,
+}
+impl Permissions {
+	/// Если файла нет, он будет создан при первом изменении прав
+	pub fn load(path: impl Into<PathBuf>) -> Result<Self, PermissionsError> {
+		JsonFile::load(path).map(|config| Self { config })
+	}
+	pub fn reload(&self) -> Result<(), PermissionsError> {
+		self.config.reload()
+	}
+
+	pub fn has_permission(&self, uuid: &str, node: &str) -> bool {
+		self.config.read().has_permission(uuid, node)
+	}
+
+	/// Изменяет конфиг и сразу сохраняет его
+	pub fn update<T>(
+		&self,
+		f: impl FnOnce(&mut PermissionsConfig) -> T,
+	) -> Result<T, PermissionsError> {
+		self.config.update(f)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const PLAYER: &str = "069a79f4-44e9-4726-a5be-fca90e38aaf5";
+
+	fn config(json: &str) -> PermissionsConfig {
+		serde_json::from_str(json).unwrap()
+	}
+
+	#[test]
+	fn wildcards() {
+		let config = config(
+			r#"{
+				"groups": {
+					"default": {
+						"permissions": {
+							"proxy.command.*": true,
+							"proxy.command.perms": false
+						}
+					},
+					"admin": { "permissions": { "*": true } }
+				},
+				"players": {
+					"069a79f4-44e9-4726-a5be-fca90e38aaf5": { "groups": ["admin"] }
+				}
+			}"#,
+		);
+		assert!(config.has_permission("someone", "proxy.command.goto"));
+		assert!(config.has_permission("someone", "proxy.command.goto.other"));
+		// Точный узел важнее шаблона
+		assert!(!config.has_permission("someone", "proxy.command.perms"));
+		assert!(!config.has_permission("someone", "proxy.server.lobby"));
+		assert!(config.has_permission(PLAYER, "proxy.server.lobby"));
+	}
+
+	#[test]
+	fn player_override_beats_groups() {
+		let config = config(
+			r#"{
+				"groups": {
+					"default": { "permissions": { "proxy.command.ping": true } },
+					"admin": { "permissions": { "*": true } }
+				},
+				"players": {
+					"069a79f4-44e9-4726-a5be-fca90e38aaf5": {
+						"groups": ["admin"],
+						"permissions": { "proxy.command.goto": false, "proxy.command.ping": false }
+					}
+				}
+			}"#,
+		);
+		assert!(!config.has_permission(PLAYER, "proxy.command.goto"));
+		assert!(!config.has_permission(PLAYER, "proxy.command.ping"));
+		assert!(config.has_permission(PLAYER, "proxy.command.perms"));
+	}
+
+	#[test]
+	fn inheritance_order() {
+		let config = config(
+			r#"{
+				"groups": {
+					"first": { "permissions": { "a": false, "b": false } },
+					"second": { "permissions": { "a": true, "b": true, "c": true } },
+					"child": {
+						"inherits": ["first", "second"],
+						"permissions": { "b": true }
+					},
+					"default": { "permissions": { "c": false, "d": true } }
+				},
+				"players": {
+					"069a79f4-44e9-4726-a5be-fca90e38aaf5": { "groups": ["child"] }
+				}
+			}"#,
+		);
+		// Первый родитель важнее второго
+		assert!(!config.has_permission(PLAYER, "a"));
+		// Своё право группы важнее родительских
+		assert!(config.has_permission(PLAYER, "b"));
+		// Явные группы игрока важнее default
+		assert!(config.has_permission(PLAYER, "c"));
+		assert!(config.has_permission(PLAYER, "d"));
+		assert!(!config.has_permission(PLAYER, "e"));
+	}
+
+	#[test]
+	fn inheritance_cycle() {
+		let config = config(
+			r#"{
+				"groups": {
+					"a": { "inherits": ["b"] },
+					"b": { "inherits": ["a"], "permissions": { "x": true } },
+					"default": { "inherits": ["a"] }
+				}
+			}"#,
+		);
+		assert!(config.has_permission("someone", "x"));
+		assert!(!config.has_permission("someone", "y"));
+	}
+}
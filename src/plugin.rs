@@ -1,18 +1,208 @@
 use std::net::SocketAddr;
 
+use async_trait::async_trait;
 use impl_trait_for_tuples::impl_for_tuples;
 
-use crate::{LoggedInInfo, protocol::login::{EncryptionRequest, EncryptionResponse}};
+use crate::{
+	limbo::{Limbo, LimboError},
+	server::ProxyServer,
+	LoggedInInfo,
+};
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Debug)]
 pub struct TargetServer {
 	pub addr: SocketAddr,
 	pub handshake_address: String,
 	pub handshake_port: i16,
 }
 
-pub trait Plugin {
-	fn get_initial_target(&self) -> Option<TargetServer> {
+/// Почему проксе понадобилось выбрать сервер для игрока
+#[derive(Debug)]
+pub enum RouteReason {
+	/// Игрок только что авторизовался
+	InitialJoin,
+	/// Текущий сервер кикнул игрока (или закрыл соединение), `reason` - json компонент
+	Kick {
+		server: TargetServer,
+		reason: String,
+	},
+	/// Игрок (или админ) запросил переход на другой сервер
+	Command { requested: TargetServer },
+}
+
+pub struct RouteContext<'i> {
+	pub proxy: &'i ProxyServer,
+	pub info: &'i LoggedInInfo,
+	pub client_addr: SocketAddr,
+	pub reason: RouteReason,
+}
+impl RouteContext<'_> {
+	pub fn handshake_host(&self) -> &str {
+		&self.info.handshake_address
+	}
+}
+
+pub enum RouteResult {
+	Target(TargetServer),
+	/// Сервера пробуются по порядку, пока один из них не примет игрока
+	Prioritized(Vec<TargetServer>),
+	/// Отключить игрока с заданной причиной
+	Disconnect(String),
+}
+
+/// Плагины объединяются кортежем `(A, B, C)` или вектором `Vec<Box<dyn Plugin>>`,
+/// при этом плагины опрашиваются по порядку:
+/// - побеждает первый вернувший маршрут плагин
+/// - `RouteResult::Disconnect` от любого плагина побеждает любой маршрут
+#[async_trait]
+pub trait Plugin: Send + Sync {
+	/// Вызывается один раз при запуске прокси, здесь плагин регистрирует обработчики событий
+	fn init(&self, _proxy: &ProxyServer) {}
+
+	async fn route(&self, _ctx: &RouteContext<'_>) -> Option<RouteResult> {
 		None
 	}
+
+	/// Вызывается перед подключением к первому серверу, игрок не попадёт на сервер, пока плагин не вернёт Ok.
+	/// Плагины объединяются по порядку, первая ошибка отключает игрока.
+	/// Игрок ещё не занял имя на прокси: его нет в списке игроков, PostLoginEvent не вызывался
+	async fn limbo(&self, _limbo: &mut Limbo<'_>) -> Result<(), LimboError> {
+		Ok(())
+	}
+}
+
+/// Объединяет ответ очередного плагина с уже выбранным, true - дальше опрашивать незачем
+fn merge_route(chosen: &mut Option<RouteResult>, result: Option<RouteResult>) -> bool {
+	match result {
+		Some(RouteResult::Disconnect(reason)) => {
+			*chosen = Some(RouteResult::Disconnect(reason));
+			true
+		}
+		Some(result) if chosen.is_none() => {
+			*chosen = Some(result);
+			false
+		}
+		_ => false,
+	}
+}
+
+#[impl_for_tuples(1, 8)]
+#[async_trait]
+impl Plugin for Tuple {
+	fn init(&self, proxy: &ProxyServer) {
+		for_tuples!( #( Tuple.init(proxy); )* );
+	}
+
+	async fn route(&self, ctx: &RouteContext<'_>) -> Option<RouteResult> {
+		let mut chosen = None;
+		for_tuples!( #(
+			if merge_route(&mut chosen, Tuple.route(ctx).await) {
+				return chosen;
+			}
+		)* );
+		chosen
+	}
+
+	async fn limbo(&self, limbo: &mut Limbo<'_>) -> Result<(), LimboError> {
+		for_tuples!( #( Tuple.limbo(limbo).await?; )* );
+		Ok(())
+	}
+}
+
+#[async_trait]
+impl<P: Plugin> Plugin for Vec<P> {
+	fn init(&self, proxy: &ProxyServer) {
+		for plugin in self {
+			plugin.init(proxy);
+		}
+	}
+
+	async fn route(&self, ctx: &RouteContext<'_>) -> Option<RouteResult> {
+		let mut chosen = None;
+		for plugin in self {
+			if merge_route(&mut chosen, plugin.route(ctx).await) {
+				break;
+			}
+		}
+		chosen
+	}
+
+	async fn limbo(&self, limbo: &mut Limbo<'_>) -> Result<(), LimboError> {
+		for plugin in self {
+			plugin.limbo(limbo).await?;
+		}
+		Ok(())
+	}
+}
+
+#[async_trait]
+impl<P: Plugin + ?Sized> Plugin for Box<P> {
+	fn init(&self, proxy: &ProxyServer) {
+		(**self).init(proxy)
+	}
+
+	async fn route(&self, ctx: &RouteContext<'_>) -> Option<RouteResult> {
+		(**self).route(ctx).await
+	}
+
+	async fn limbo(&self, limbo: &mut Limbo<'_>) -> Result<(), LimboError> {
+		(**self).limbo(limbo).await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn target(port: u16) -> TargetServer {
+		TargetServer {
+			addr: ([127, 0, 0, 1], port).into(),
+			handshake_address: "localhost".to_owned(),
+			handshake_port: port as i16,
+		}
+	}
+
+	/// Объединяет ответы плагинов по порядку, как это делают кортеж и Vec
+	fn merge(results: Vec<Option<RouteResult>>) -> Option<RouteResult> {
+		let mut chosen = None;
+		for result in results {
+			if merge_route(&mut chosen, result) {
+				break;
+			}
+		}
+		chosen
+	}
+
+	#[test]
+	fn first_target_wins() {
+		let chosen = merge(vec![
+			None,
+			Some(RouteResult::Target(target(1))),
+			Some(RouteResult::Prioritized(vec![target(2)])),
+			Some(RouteResult::Target(target(3))),
+		]);
+		assert!(matches!(chosen, Some(RouteResult::Target(t)) if t == target(1)));
+		assert!(merge(vec![None, None]).is_none());
+	}
+
+	#[test]
+	fn any_disconnect_wins() {
+		let chosen = merge(vec![
+			Some(RouteResult::Target(target(1))),
+			Some(RouteResult::Disconnect("first".to_owned())),
+			Some(RouteResult::Disconnect("second".to_owned())),
+		]);
+		assert!(matches!(chosen, Some(RouteResult::Disconnect(reason)) if reason == "first"));
+		// Disconnect прекращает опрос
+		let mut chosen = None;
+		assert!(merge_route(
+			&mut chosen,
+			Some(RouteResult::Disconnect("kick".to_owned()))
+		));
+		assert!(!merge_route(
+			&mut chosen,
+			Some(RouteResult::Target(target(1)))
+		));
+		assert!(matches!(chosen, Some(RouteResult::Disconnect(_))));
+	}
 }
@@ -0,0 +1,172 @@
+use std::{
+	io,
+	path::PathBuf,
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+	json_file::JsonFile,
+	plugins::auth::{AuthError, AuthFilter, AuthSucceeded},
+};
+
+#[derive(Debug, Error)]
+pub enum AccessListError {
+	#[error("io error: {0}")]
+	Io(#[from] io::Error),
+	#[error("bad list file: {0}")]
+	Json(#[from] serde_json::Error),
+}
+
+fn now() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap()
+		.as_secs()
+}
+
+/// Игрок в списке указывается по UUID, по имени (без учёта регистра), либо по обоим
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PlayerEntry {
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub uuid: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub name: Option<String>,
+}
+impl PlayerEntry {
+	fn matches(&self, player: &AuthSucceeded) -> bool {
+		self.uuid.as_deref() == Some(&player.uuid)
+			|| matches!(&self.name, Some(name) if name.eq_ignore_ascii_case(&player.username))
+	}
+	/// По имени либо UUID
+	fn is(&self, player: &str) -> bool {
+		self.uuid.as_deref() == Some(player)
+			|| matches!(&self.name, Some(name) if name.eq_ignore_ascii_case(player))
+	}
+}
+
+/// Пускает только игроков из списка
+pub struct Whitelist {
+	list: JsonFile<Vec<PlayerEntry>>,
+}
+impl Whitelist {
+	pub fn load(path: impl Into<PathBuf>) -> Result<Self, AccessListError> {
+		JsonFile::load(path).map(|list| Self { list })
+	}
+	pub fn reload(&self) -> Result<(), AccessListError> {
+		self.list.reload()
+	}
+	pub fn add(&self, entry: PlayerEntry) -> Result<(), AccessListError> {
+		self.list.update(|entries| entries.push(entry))
+	}
+	pub fn remove(&self, player: &str) -> Result<(), AccessListError> {
+		self.list
+			.update(|entries| entries.retain(|entry| !entry.is(player)))
+	}
+}
+#[async_trait]
+impl AuthFilter for Whitelist {
+	async fn filter(&self, success: AuthSucceeded) -> Result<AuthSucceeded, AuthError> {
+		let whitelisted = self.list.read().iter().any(|entry| entry.matches(&success));
+		if whitelisted {
+			Ok(success)
+		} else {
+			Err(AuthError::Denied(
+				"You are not whitelisted on this server".to_owned(),
+			))
+		}
+	}
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BanEntry {
+	#[serde(flatten)]
+	pub player: PlayerEntry,
+	pub reason: String,
+	/// Кто выдал бан
+	pub operator: String,
+	/// Unix время в секундах
+	pub created: u64,
+	/// Unix время в секундах, None - бан навсегда
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub expires: Option<u64>,
+}
+impl BanEntry {
+	pub fn new(
+		player: PlayerEntry,
+		reason: impl Into<String>,
+		operator: impl Into<String>,
+	) -> Self {
+		Self {
+			player,
+			reason: reason.into(),
+			operator: operator.into(),
+			created: now(),
+			expires: None,
+		}
+	}
+	/// Бан на заданное число секунд с текущего момента
+	pub fn temporary(mut self, seconds: u64) -> Self {
+		self.expires = Some(now() + seconds);
+		self
+	}
+	fn is_active(&self, now: u64) -> bool {
+		self.expires.is_none_or(|expires| expires > now)
+	}
+	fn message(&self, now: u64) -> String {
+		let mut message = format!(
+			"You are banned from this server\nReason: {}\nBanned by: {}",
+			self.reason, self.operator
+		);
+		if let Some(expires) = self.expires {
+			let left = expires.saturating_sub(now);
+			message.push_str(&format!(
+				"\nExpires in: {}h {}m",
+				left / 3600,
+				left % 3600 / 60
+			));
+		}
+		message
+	}
+}
+
+/// Не пускает забаненных игроков, истёкшие баны игнорируются.
+/// Формат файла свой, `banned-players.json` ванильного сервера не подходит
+pub struct BanList {
+	list: JsonFile<Vec<BanEntry>>,
+}
+impl BanList {
+	pub fn load(path: impl Into<PathBuf>) -> Result<Self, AccessListError> {
+		JsonFile::load(path).map(|list| Self { list })
+	}
+	pub fn reload(&self) -> Result<(), AccessListError> {
+		self.list.reload()
+	}
+	pub fn ban(&self, entry: BanEntry) -> Result<(), AccessListError> {
+		self.list.update(|entries| entries.push(entry))
+	}
+	/// Снимает все баны игрока, по имени либо UUID
+	pub fn pardon(&self, player: &str) -> Result<(), AccessListError> {
+		self.list
+			.update(|entries| entries.retain(|entry| !entry.player.is(player)))
+	}
+}
+#[async_trait]
+impl AuthFilter for BanList {
+	async fn filter(&self, success: AuthSucceeded) -> Result<AuthSucceeded, AuthError> {
+		let now = now();
+		let message = self
+			.list
+			.read()
+			.iter()
+			.find(|entry| entry.is_active(now) && entry.player.matches(&success))
+			.map(|entry| entry.message(now));
+		match message {
+			Some(message) => Err(AuthError::Denied(message)),
+			None => Ok(success),
+		}
+	}
+}
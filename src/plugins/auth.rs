@@ -1,12 +1,20 @@
 use async_trait::async_trait;
+use impl_trait_for_tuples::impl_for_tuples;
 use num_bigint_dig::{BigInt, Sign};
 use rand::{rngs::OsRng, thread_rng, Rng};
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
 use rsa::{PaddingScheme, PublicKeyParts, RSAPrivateKey, RSAPublicKey};
 use rsa_der::public_key_to_der;
-use serde::Deserialize;
-use std::convert::Infallible;
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::HashMap,
+	convert::Infallible,
+	net::{IpAddr, SocketAddr},
+	sync::Mutex,
+	time::{Duration, Instant},
+};
 use thiserror::Error;
+use tokio::{sync::Semaphore, time::sleep};
 use uuid::Uuid;
 
 use crate::protocol::login::{EncryptionRequest, EncryptionResponse};
@@ -21,13 +29,29 @@ pub enum AuthError {
 	BadSharedSecret,
 	#[error("unexpected encryption response")]
 	Unsupported,
+	#[error("no session server confirmed the login")]
+	NotAuthenticated,
+	/// Игроку отказано во входе, текст будет показан ему
+	#[error("login denied: {0}")]
+	Denied(String),
 	#[error(transparent)]
 	Other(anyhow::Error),
 }
 
+/// Свойство профиля с сессионного сервера, например `textures` со скином
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ProfileProperty {
+	pub name: String,
+	pub value: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub signature: Option<String>,
+}
+
+#[derive(Clone)]
 pub struct AuthSucceeded {
 	pub username: String,
 	pub uuid: String,
+	pub properties: Vec<ProfileProperty>,
 }
 
 pub enum EncryptionStartResult<D = Infallible> {
@@ -38,39 +62,157 @@ pub enum EncryptionStartResult<D = Infallible> {
 #[async_trait]
 pub trait AuthPlugin: Sync {
 	type AuthData: Send;
-	fn encryption_start(&self, name: String) -> EncryptionStartResult<Self::AuthData>;
+	async fn encryption_start(
+		&self,
+		name: String,
+	) -> Result<EncryptionStartResult<Self::AuthData>, AuthError>;
+	/// `client_addr` - адрес игрока, с PROXY protocol - настоящий, переданный балансировщиком
 	async fn encryption_response(
 		&self,
 		_data: Self::AuthData,
 		_res: EncryptionResponse,
+		_client_addr: SocketAddr,
 	) -> Result<AuthSucceeded, AuthError> {
 		Err(AuthError::Unsupported)
 	}
+	/// Вызывается после успешной авторизации любым из способов, может отказать игроку во входе
+	async fn verify(&self, success: AuthSucceeded) -> Result<AuthSucceeded, AuthError> {
+		Ok(success)
+	}
+}
+
+/// Проверка игрока, уже авторизованного внутренним AuthPlugin.
+/// Фильтры объединяются кортежем `(A, B, C)` или вектором `Vec<Box<dyn AuthFilter>>`
+/// и вызываются по порядку, первый отказ прерывает вход
+#[async_trait]
+pub trait AuthFilter: Send + Sync {
+	async fn filter(&self, success: AuthSucceeded) -> Result<AuthSucceeded, AuthError>;
+}
+
+#[impl_for_tuples(1, 8)]
+#[async_trait]
+impl AuthFilter for Tuple {
+	async fn filter(&self, success: AuthSucceeded) -> Result<AuthSucceeded, AuthError> {
+		for_tuples!( #( let success = Tuple.filter(success).await?; )* );
+		Ok(success)
+	}
+}
+
+#[async_trait]
+impl<F: AuthFilter> AuthFilter for Vec<F> {
+	async fn filter(&self, mut success: AuthSucceeded) -> Result<AuthSucceeded, AuthError> {
+		for filter in self {
+			success = filter.filter(success).await?;
+		}
+		Ok(success)
+	}
+}
+
+#[async_trait]
+impl<F: AuthFilter + ?Sized> AuthFilter for Box<F> {
+	async fn filter(&self, success: AuthSucceeded) -> Result<AuthSucceeded, AuthError> {
+		(**self).filter(success).await
+	}
+}
+
+/// Авторизует игрока внутренним плагином, после чего прогоняет результат через фильтр
+pub struct Filtered<A, F> {
+	inner: A,
+	filter: F,
+}
+impl<A, F> Filtered<A, F> {
+	pub fn new(inner: A, filter: F) -> Self {
+		Self { inner, filter }
+	}
+}
+#[async_trait]
+impl<A: AuthPlugin, F: AuthFilter> AuthPlugin for Filtered<A, F> {
+	type AuthData = A::AuthData;
+	async fn encryption_start(
+		&self,
+		name: String,
+	) -> Result<EncryptionStartResult<Self::AuthData>, AuthError> {
+		self.inner.encryption_start(name).await
+	}
+	async fn encryption_response(
+		&self,
+		data: Self::AuthData,
+		res: EncryptionResponse,
+		client_addr: SocketAddr,
+	) -> Result<AuthSucceeded, AuthError> {
+		self.inner.encryption_response(data, res, client_addr).await
+	}
+	async fn verify(&self, success: AuthSucceeded) -> Result<AuthSucceeded, AuthError> {
+		let success = self.inner.verify(success).await?;
+		self.filter.filter(success).await
+	}
 }
 
 pub struct OfflineAuthPlugin;
+#[async_trait]
 impl AuthPlugin for OfflineAuthPlugin {
 	type AuthData = Infallible;
-	fn encryption_start(&self, name: String) -> EncryptionStartResult {
+	async fn encryption_start(&self, name: String) -> Result<EncryptionStartResult, AuthError> {
 		let input = format!("OfflinePlayer:{}", name);
 		let mut hash = md5::compute(input).0;
 		hash[6] = hash[6] & 0x0f | 0x30;
 		hash[8] = hash[8] & 0x3f | 0x80;
-		EncryptionStartResult::Skip(AuthSucceeded {
+		Ok(EncryptionStartResult::Skip(AuthSucceeded {
 			username: name,
 			uuid: Uuid::from_slice(&hash).unwrap().to_string(),
-		})
+			properties: Vec::new(),
+		}))
+	}
+}
+
+/// sha1 в виде, в котором его считает Minecraft: знаковое число в hex, без ведущих нулей
+pub fn minecraft_digest(hash: [u8; 20]) -> String {
+	let negative = hash[0] & 0x80 != 0;
+	let mut bytes = hash;
+	if negative {
+		// Модуль отрицательного числа в дополнительном коде
+		let mut carry = true;
+		for byte in bytes.iter_mut().rev() {
+			let (value, overflow) = (!*byte).overflowing_add(carry as u8);
+			*byte = value;
+			carry = overflow;
+		}
+	}
+	let hex = hex::encode(bytes);
+	let hex = match hex.trim_start_matches('0') {
+		"" => "0",
+		hex => hex,
+	};
+	if negative {
+		format!("-{}", hex)
+	} else {
+		hex.to_owned()
 	}
 }
 
+pub const MOJANG_SESSION_SERVER: &str =
+	"https://sessionserver.mojang.com/session/minecraft/hasJoined";
+
 pub struct MojangAuthPlugin {
 	private: RSAPrivateKey,
 	// public: RSAPublicKey,
 	public_der: Vec<u8>,
 	client: Client,
+	session_servers: Vec<String>,
+	timeout: Duration,
+	retries: u32,
+	backoff: Duration,
+	/// Ограничивает число одновременных запросов к серверам сессий
+	requests: Semaphore,
+	/// Имя в нижнем регистре -> последний подтверждённый вход, хранится только при `offline_grace`
+	recent: Mutex<HashMap<String, RecentLogin>>,
+	/// Сколько помнить подтверждённый вход на случай недоступности серверов сессий,
+	/// None - не пускать без сервера сессий
+	offline_grace: Option<Duration>,
+	prevent_proxy: bool,
 }
 impl MojangAuthPlugin {
-	fn new(private: RSAPrivateKey) -> Self {
+	pub fn new(private: RSAPrivateKey) -> Self {
 		let public: RSAPublicKey = private.clone().into();
 		let public_der = public_key_to_der(
 			&BigInt::from_biguint(Sign::Plus, public.n().clone()).to_signed_bytes_be(),
@@ -81,17 +223,171 @@ impl MojangAuthPlugin {
 			// public,
 			public_der,
 			client: Client::new(),
+			session_servers: vec![MOJANG_SESSION_SERVER.to_owned()],
+			timeout: Duration::from_secs(5),
+			retries: 2,
+			backoff: Duration::from_millis(250),
+			requests: Semaphore::new(16),
+			recent: Mutex::new(HashMap::new()),
+			offline_grace: None,
+			prevent_proxy: false,
 		}
 	}
 	pub fn with_generated_keypair() -> Self {
 		Self::new(RSAPrivateKey::new(&mut OsRng, 1024).unwrap())
 	}
+	/// Полные адреса hasJoined, опрашиваются по порядку, пока один из них не подтвердит вход.
+	/// Для authlib-injector это `<api>/sessionserver/session/minecraft/hasJoined`,
+	/// для Ely.by - `https://authserver.ely.by/session/hasJoined`
+	pub fn with_session_servers(mut self, urls: Vec<String>) -> Self {
+		self.session_servers = urls;
+		self
+	}
+	/// Таймаут одного запроса к серверу сессий
+	pub fn with_timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = timeout;
+		self
+	}
+	/// Остальные входы ждут, пока освободится место
+	pub fn with_max_concurrent_requests(mut self, max: usize) -> Self {
+		self.requests = Semaphore::new(max);
+		self
+	}
+	/// Повторы при сетевых ошибках и ответах 5xx/429, пауза удваивается с каждым повтором
+	pub fn with_retries(mut self, retries: u32, backoff: Duration) -> Self {
+		self.retries = retries;
+		self.backoff = backoff;
+		self
+	}
+	/// Если все серверы сессий недоступны, пускать игроков, чей вход подтверждался
+	/// за последние `grace` с того же IP, с тем же профилем.
+	/// Ответ "вход не найден" по-прежнему отказывает во входе
+	pub fn with_offline_grace(mut self, grace: Duration) -> Self {
+		self.offline_grace = Some(grace);
+		self
+	}
+	/// Передавать серверу сессий IP игрока (`prevent-proxy-connections` в ванилле):
+	/// вход подтвердится, только если клиент обращался к серверу сессий с того же адреса,
+	/// так украденный токен сессии нельзя использовать с другой машины
+	pub fn with_prevent_proxy(mut self, enabled: bool) -> Self {
+		self.prevent_proxy = enabled;
+		self
+	}
+
+	/// None - сервер не знает о таком входе
+	async fn has_joined(
+		&self,
+		url: &str,
+		name: &str,
+		server_id: &str,
+		ip: Option<IpAddr>,
+	) -> Result<Option<HasJoinedResponse>, AuthError> {
+		let _permit = self
+			.requests
+			.acquire()
+			.await
+			.map_err(|e| AuthError::Other(e.into()))?;
+		let mut request = self
+			.client
+			.get(url)
+			.query(&[("username", name), ("serverId", server_id)])
+			.query(&[("unsigned", false)]);
+		if let Some(ip) = ip {
+			request = request.query(&[("ip", ip.to_string())]);
+		}
+		let response = request
+			.timeout(self.timeout)
+			.send()
+			.await
+			.map_err(|e| AuthError::Other(e.into()))?;
+		let status = response.status();
+		if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+			return Err(AuthError::Other(anyhow::anyhow!(
+				"session server returned {}",
+				status
+			)));
+		}
+		if !status.is_success() {
+			return Ok(None);
+		}
+		let body = response
+			.text()
+			.await
+			.map_err(|e| AuthError::Other(e.into()))?;
+		if body.trim().is_empty() {
+			return Ok(None);
+		}
+		serde_json::from_str(&body)
+			.map(Some)
+			.map_err(|e| AuthError::Other(e.into()))
+	}
+
+	/// Ошибки повторяются, ответ сервера (в том числе отказ) - нет
+	async fn has_joined_with_retries(
+		&self,
+		url: &str,
+		name: &str,
+		server_id: &str,
+		ip: Option<IpAddr>,
+	) -> Result<Option<HasJoinedResponse>, AuthError> {
+		let mut backoff = self.backoff;
+		let mut attempt = 0;
+		loop {
+			match self.has_joined(url, name, server_id, ip).await {
+				Err(e) if attempt < self.retries => {
+					println!("Session server {} failed, retrying: {}", url, e);
+					sleep(backoff).await;
+					backoff *= 2;
+					attempt += 1;
+				}
+				result => return result,
+			}
+		}
+	}
+
+	fn remember(&self, success: &AuthSucceeded, ip: IpAddr) {
+		let keep = match self.offline_grace {
+			Some(grace) => grace,
+			None => return,
+		};
+		let now = Instant::now();
+		let mut recent = self.recent.lock().unwrap();
+		recent.retain(|_, login| now.duration_since(login.seen) < keep);
+		recent.insert(
+			success.username.to_lowercase(),
+			RecentLogin {
+				seen: now,
+				ip,
+				success: success.clone(),
+			},
+		);
+	}
+
+	/// Вход, подтверждённый не раньше `within` назад для того же IP
+	fn recent_login(&self, name: &str, ip: IpAddr, within: Duration) -> Option<AuthSucceeded> {
+		let recent = self.recent.lock().unwrap();
+		match recent.get(&name.to_lowercase()) {
+			Some(login) if login.ip == ip && login.seen.elapsed() < within => {
+				Some(login.success.clone())
+			}
+			_ => None,
+		}
+	}
+}
+
+struct RecentLogin {
+	/// Когда вход подтвердил сервер сессий, повторное использование время не продлевает
+	seen: Instant,
+	ip: IpAddr,
+	success: AuthSucceeded,
 }
 
 #[derive(Deserialize)]
 pub struct HasJoinedResponse {
 	pub id: Uuid,
 	pub name: String,
+	#[serde(default)]
+	pub properties: Vec<ProfileProperty>,
 }
 
 pub struct AuthlibAuthData {
@@ -101,9 +397,12 @@ pub struct AuthlibAuthData {
 #[async_trait]
 impl AuthPlugin for MojangAuthPlugin {
 	type AuthData = AuthlibAuthData;
-	fn encryption_start(&self, name: String) -> EncryptionStartResult<Self::AuthData> {
+	async fn encryption_start(
+		&self,
+		name: String,
+	) -> Result<EncryptionStartResult<Self::AuthData>, AuthError> {
 		let verify_token: Vec<u8> = thread_rng().gen::<[u8; 4]>().into();
-		EncryptionStartResult::BeginEncryption(
+		Ok(EncryptionStartResult::BeginEncryption(
 			EncryptionRequest {
 				public: self.public_der.clone(),
 				server_id: "".into(),
@@ -113,12 +412,13 @@ impl AuthPlugin for MojangAuthPlugin {
 				verify_token,
 				name: name.clone(),
 			},
-		)
+		))
 	}
 	async fn encryption_response(
 		&self,
 		data: Self::AuthData,
 		res: EncryptionResponse,
+		client_addr: SocketAddr,
 	) -> Result<AuthSucceeded, AuthError> {
 		let verify_token = self
 			.private
@@ -139,25 +439,413 @@ impl AuthPlugin for MojangAuthPlugin {
 		hash.update(b"");
 		hash.update(&shared_secret);
 		hash.update(&self.public_der);
-		let hash_hex = hex::encode(hash.digest().bytes());
+		let hash_hex = minecraft_digest(hash.digest().bytes());
 
-		let result = self
-			.client
-			.get("https://sessionserver.mojang.com/session/minecraft/hasJoined")
-			.query(&[("username", &data.name), ("serverId", &hash_hex)])
-			.query(&[("unsigned", false)])
-			.send()
-			.await
-			.map_err(|e| AuthError::Other(e.into()))?
-			.text()
-			.await
-			.map_err(|e| AuthError::Other(e.into()))?;
-		dbg!(&result);
-		let result: HasJoinedResponse =
-			serde_json::from_str(&result).map_err(|e| AuthError::Other(e.into()))?;
-		Ok(AuthSucceeded {
+		let client_ip = client_addr.ip();
+		let ip = Some(client_ip).filter(|_| self.prevent_proxy);
+
+		// Ошибка одного сервера не мешает спросить следующий
+		let mut error = None;
+		let mut answered = false;
+		let mut result = None;
+		for url in &self.session_servers {
+			match self
+				.has_joined_with_retries(url, &data.name, &hash_hex, ip)
+				.await
+			{
+				Ok(Some(response)) => {
+					result = Some(response);
+					break;
+				}
+				Ok(None) => answered = true,
+				Err(e) => error = Some(e),
+			}
+		}
+		let result = match (result, error) {
+			(Some(result), _) => result,
+			(None, Some(error)) if !answered => {
+				// Ни один сервер не ответил - пускаем недавно подтверждённых игроков
+				let grace = self.offline_grace.unwrap_or_default();
+				return match self.recent_login(&data.name, client_ip, grace) {
+					Some(success) => {
+						println!(
+							"Session servers are unavailable, letting in recently seen {}",
+							success.username
+						);
+						Ok(success)
+					}
+					None => Err(error),
+				};
+			}
+			_ => return Err(AuthError::NotAuthenticated),
+		};
+		let success = AuthSucceeded {
 			username: result.name,
 			uuid: result.id.to_string(),
+			properties: result.properties,
+		};
+		self.remember(&success, client_ip);
+		Ok(success)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::plugins::test_http;
+	use num_bigint_dig::BigUint;
+	use rsa::PublicKey;
+	use std::sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc,
+	};
+
+	fn sha1(data: &[u8]) -> [u8; 20] {
+		sha1::Sha1::from(data).digest().bytes()
+	}
+
+	#[test]
+	fn digest_known_vectors() {
+		assert_eq!(
+			minecraft_digest(sha1(b"Notch")),
+			"4ed1f46bbe04bc756bcb17c0c7ce3e4632f06a48"
+		);
+		assert_eq!(
+			minecraft_digest(sha1(b"jeb_")),
+			"-7c9d5b0044c130109a5d7b5fb5c317c02b4e28c1"
+		);
+		assert_eq!(
+			minecraft_digest(sha1(b"simon")),
+			"88e16a1019277b15d58faf0541e11910eb756f6"
+		);
+	}
+
+	#[test]
+	fn digest_edge_cases() {
+		assert_eq!(minecraft_digest([0; 20]), "0");
+		assert_eq!(minecraft_digest([0xff; 20]), "-1");
+		let mut min = [0; 20];
+		min[0] = 0x80;
+		assert_eq!(minecraft_digest(min), format!("-8{}", "0".repeat(39)));
+	}
+
+	const CLIENT_ADDR: &str = "203.0.113.7:51234";
+
+	/// Отвечает профилем только на hasJoined с ожидаемыми именем, serverId и ip, если он задан
+	async fn fake_session_server(
+		username: &'static str,
+		server_id: String,
+		ip: Option<&'static str>,
+	) -> String {
+		scripted_session_server(username, server_id, ip, Vec::new())
+			.await
+			.0
+	}
+
+	/// Как fake_session_server, но первые запросы получают ответы с кодами из `errors`
+	/// (например `"500 Internal Server Error"`). Возвращает адрес и счётчик запросов
+	async fn scripted_session_server(
+		username: &'static str,
+		server_id: String,
+		ip: Option<&'static str>,
+		errors: Vec<&'static str>,
+	) -> (String, Arc<AtomicUsize>) {
+		let (addr, requests) = test_http::serve(move |number, path| {
+			let expected = format!("username={}&serverId={}", username, server_id);
+			let ip_matches = match ip {
+				Some(ip) => path.contains(&format!("ip={}", ip)),
+				None => !path.contains("ip="),
+			};
+			if let Some(status) = errors.get(number) {
+				test_http::response(status, "")
+			} else if path.contains(&expected) && ip_matches {
+				test_http::response(
+					"200 OK",
+					&format!(
+						r#"{{"id":"069a79f444e94726a5befca90e38aaf5","name":"{}","properties":[{{"name":"textures","value":"dGV4dHVyZXM=","signature":"c2lnbmF0dXJl"}}]}}"#,
+						username
+					),
+				)
+			} else {
+				test_http::response("204 No Content", "")
+			}
 		})
+		.await;
+		(
+			format!("http://{}/session/minecraft/hasJoined", addr),
+			requests,
+		)
+	}
+
+	/// Принимает соединения и ничего не отвечает
+	async fn silent_session_server() -> String {
+		format!(
+			"http://{}/session/minecraft/hasJoined",
+			test_http::silent().await
+		)
+	}
+
+	/// Шифрует общий секрет и verify token так же, как это делает клиент
+	fn client_response(request: &EncryptionRequest, shared_secret: &[u8]) -> EncryptionResponse {
+		let (n, e) = rsa_der::public_key_from_der(&request.public).unwrap();
+		let public =
+			RSAPublicKey::new(BigUint::from_bytes_be(&n), BigUint::from_bytes_be(&e)).unwrap();
+		let mut rng = OsRng;
+		EncryptionResponse {
+			shared_secret: public
+				.encrypt(&mut rng, PaddingScheme::PKCS1v15Encrypt, shared_secret)
+				.unwrap(),
+			verify_token: public
+				.encrypt(
+					&mut rng,
+					PaddingScheme::PKCS1v15Encrypt,
+					&request.verify_token,
+				)
+				.unwrap(),
+		}
+	}
+
+	async fn begin(plugin: &MojangAuthPlugin, name: &str) -> (EncryptionRequest, AuthlibAuthData) {
+		match plugin.encryption_start(name.to_owned()).await.unwrap() {
+			EncryptionStartResult::BeginEncryption(request, data) => (request, data),
+			EncryptionStartResult::Skip(_) => panic!("online auth must request encryption"),
+		}
+	}
+
+	fn server_id(request: &EncryptionRequest, shared_secret: &[u8]) -> String {
+		let mut hash = sha1::Sha1::new();
+		hash.update(request.server_id.as_bytes());
+		hash.update(shared_secret);
+		hash.update(&request.public);
+		minecraft_digest(hash.digest().bytes())
+	}
+
+	#[tokio::test]
+	async fn online_login_against_fake_session_server() {
+		let plugin = MojangAuthPlugin::with_generated_keypair();
+		let shared_secret = [7; 16];
+		let (request, data) = begin(&plugin, "Notch").await;
+		let server_id = server_id(&request, &shared_secret);
+
+		let plugin =
+			plugin.with_session_servers(vec![fake_session_server("Notch", server_id, None).await]);
+		let response = client_response(&request, &shared_secret);
+		let success = plugin
+			.encryption_response(data, response, CLIENT_ADDR.parse().unwrap())
+			.await
+			.unwrap();
+		assert_eq!(success.username, "Notch");
+		assert_eq!(success.uuid, "069a79f4-44e9-4726-a5be-fca90e38aaf5");
+		assert_eq!(success.properties.len(), 1);
+		assert_eq!(success.properties[0].name, "textures");
+		assert_eq!(
+			success.properties[0].signature.as_deref(),
+			Some("c2lnbmF0dXJl")
+		);
+	}
+
+	#[tokio::test]
+	async fn login_with_wrong_secret_is_rejected() {
+		let plugin = MojangAuthPlugin::with_generated_keypair();
+		let (request, data) = begin(&plugin, "Notch").await;
+		let plugin = plugin.with_session_servers(vec![
+			fake_session_server("Notch", "not-this-one".to_owned(), None).await,
+		]);
+		let response = client_response(&request, &[7; 16]);
+		assert!(matches!(
+			plugin
+				.encryption_response(data, response, CLIENT_ADDR.parse().unwrap())
+				.await,
+			Err(AuthError::NotAuthenticated)
+		));
+	}
+
+	#[tokio::test]
+	async fn prevent_proxy_sends_client_ip() {
+		let shared_secret = [7; 16];
+		for (expected_ip, authenticated) in [("203.0.113.7", true), ("198.51.100.1", false)] {
+			let plugin = MojangAuthPlugin::with_generated_keypair().with_prevent_proxy(true);
+			let (request, data) = begin(&plugin, "Notch").await;
+			let server = fake_session_server(
+				"Notch",
+				server_id(&request, &shared_secret),
+				Some(expected_ip),
+			)
+			.await;
+			let plugin = plugin.with_session_servers(vec![server]);
+			let response = client_response(&request, &shared_secret);
+			let result = plugin
+				.encryption_response(data, response, CLIENT_ADDR.parse().unwrap())
+				.await;
+			assert_eq!(result.is_ok(), authenticated);
+		}
+	}
+
+	#[tokio::test]
+	async fn bad_verify_token_is_rejected() {
+		let plugin = MojangAuthPlugin::with_generated_keypair();
+		let (mut request, data) = begin(&plugin, "Notch").await;
+		request.verify_token = vec![0; 4];
+		let response = client_response(&request, &[7; 16]);
+		assert!(matches!(
+			plugin
+				.encryption_response(data, response, CLIENT_ADDR.parse().unwrap())
+				.await,
+			Err(AuthError::BadVerifyToken)
+		));
+	}
+
+	const SHARED_SECRET: [u8; 16] = [7; 16];
+
+	/// Весь вход целиком, как его проводит клиент
+	async fn login(
+		plugin: &MojangAuthPlugin,
+		client_addr: &str,
+	) -> Result<AuthSucceeded, AuthError> {
+		let (request, data) = begin(plugin, "Notch").await;
+		let response = client_response(&request, &SHARED_SECRET);
+		plugin
+			.encryption_response(data, response, client_addr.parse().unwrap())
+			.await
+	}
+
+	/// serverId, который получится у клиента при входе на этот плагин
+	async fn expected_server_id(plugin: &MojangAuthPlugin) -> String {
+		let (request, _) = begin(plugin, "Notch").await;
+		server_id(&request, &SHARED_SECRET)
+	}
+
+	fn fast_retries(plugin: MojangAuthPlugin, retries: u32) -> MojangAuthPlugin {
+		plugin.with_retries(retries, Duration::from_millis(1))
+	}
+
+	#[tokio::test]
+	async fn server_errors_are_retried() {
+		let plugin = fast_retries(MojangAuthPlugin::with_generated_keypair(), 2);
+		let (url, requests) = scripted_session_server(
+			"Notch",
+			expected_server_id(&plugin).await,
+			None,
+			vec!["500 Internal Server Error", "429 Too Many Requests"],
+		)
+		.await;
+		let plugin = plugin.with_session_servers(vec![url]);
+		assert_eq!(login(&plugin, CLIENT_ADDR).await.unwrap().username, "Notch");
+		assert_eq!(requests.load(Ordering::SeqCst), 3);
+	}
+
+	#[tokio::test]
+	async fn retries_are_limited() {
+		let plugin = fast_retries(MojangAuthPlugin::with_generated_keypair(), 1);
+		let (url, requests) = scripted_session_server(
+			"Notch",
+			expected_server_id(&plugin).await,
+			None,
+			vec!["503 Service Unavailable"; 5],
+		)
+		.await;
+		let plugin = plugin.with_session_servers(vec![url]);
+		assert!(matches!(
+			login(&plugin, CLIENT_ADDR).await,
+			Err(AuthError::Other(_))
+		));
+		assert_eq!(requests.load(Ordering::SeqCst), 2);
+	}
+
+	#[tokio::test]
+	async fn not_joined_is_not_retried() {
+		let plugin = fast_retries(MojangAuthPlugin::with_generated_keypair(), 2);
+		let (url, requests) =
+			scripted_session_server("Notch", "not-this-one".to_owned(), None, Vec::new()).await;
+		let plugin = plugin.with_session_servers(vec![url]);
+		assert!(matches!(
+			login(&plugin, CLIENT_ADDR).await,
+			Err(AuthError::NotAuthenticated)
+		));
+		assert_eq!(requests.load(Ordering::SeqCst), 1);
+	}
+
+	#[tokio::test]
+	async fn hanging_session_server_times_out() {
+		let plugin = fast_retries(MojangAuthPlugin::with_generated_keypair(), 0)
+			.with_timeout(Duration::from_millis(200))
+			.with_session_servers(vec![silent_session_server().await]);
+		let result = tokio::time::timeout(Duration::from_secs(5), login(&plugin, CLIENT_ADDR))
+			.await
+			.expect("request timeout must fire first");
+		assert!(matches!(result, Err(AuthError::Other(_))));
+	}
+
+	#[tokio::test]
+	async fn every_login_asks_session_server() {
+		let plugin =
+			MojangAuthPlugin::with_generated_keypair().with_offline_grace(Duration::from_secs(60));
+		let (url, requests) =
+			scripted_session_server("Notch", expected_server_id(&plugin).await, None, Vec::new())
+				.await;
+		let plugin = plugin.with_session_servers(vec![url]);
+		login(&plugin, CLIENT_ADDR).await.unwrap();
+		login(&plugin, CLIENT_ADDR).await.unwrap();
+		assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+		// Сервер сессий доступен и входа не подтверждает - прошлый вход с того же IP не помогает
+		let plugin = plugin.with_session_servers(vec![
+			fake_session_server("Notch", "not-this-one".to_owned(), None).await,
+		]);
+		assert!(matches!(
+			login(&plugin, CLIENT_ADDR).await,
+			Err(AuthError::NotAuthenticated)
+		));
+	}
+
+	#[tokio::test]
+	async fn offline_grace_lets_in_recent_player_from_same_ip() {
+		let plugin = fast_retries(MojangAuthPlugin::with_generated_keypair(), 0)
+			.with_offline_grace(Duration::from_secs(60));
+		let server_id = expected_server_id(&plugin).await;
+		let plugin = plugin.with_session_servers(vec![
+			fake_session_server("Notch", server_id.clone(), None).await,
+		]);
+		login(&plugin, CLIENT_ADDR).await.unwrap();
+
+		// Сервер сессий лежит
+		let (down, _) =
+			scripted_session_server("Notch", server_id, None, vec!["502 Bad Gateway"; 5]).await;
+		let plugin = plugin.with_session_servers(vec![down]);
+		let success = login(&plugin, CLIENT_ADDR).await.unwrap();
+		assert_eq!(success.uuid, "069a79f4-44e9-4726-a5be-fca90e38aaf5");
+		assert_eq!(success.properties.len(), 1);
+		// С другого адреса чужой профиль не выдаётся
+		assert!(matches!(
+			login(&plugin, "198.51.100.1:40000").await,
+			Err(AuthError::Other(_))
+		));
+
+		// Сервер отвечает, что входа не было - льгота не действует
+		let plugin = plugin.with_session_servers(vec![
+			fake_session_server("Notch", "not-this-one".to_owned(), None).await,
+		]);
+		assert!(matches!(
+			login(&plugin, CLIENT_ADDR).await,
+			Err(AuthError::NotAuthenticated)
+		));
+	}
+
+	#[tokio::test]
+	async fn outage_without_grace_is_refused() {
+		let plugin = fast_retries(MojangAuthPlugin::with_generated_keypair(), 0);
+		let server_id = expected_server_id(&plugin).await;
+		let plugin = plugin.with_session_servers(vec![
+			fake_session_server("Notch", server_id.clone(), None).await,
+		]);
+		login(&plugin, CLIENT_ADDR).await.unwrap();
+		let (down, _) =
+			scripted_session_server("Notch", server_id, None, vec!["500 Internal Server Error"])
+				.await;
+		let plugin = plugin.with_session_servers(vec![down]);
+		assert!(matches!(
+			login(&plugin, CLIENT_ADDR).await,
+			Err(AuthError::Other(_))
+		));
 	}
 }
@@ -0,0 +1,46 @@
+use async_trait::async_trait;
+
+use crate::{
+	chat,
+	plugin::{Plugin, RouteContext, RouteReason, RouteResult, TargetServer},
+};
+
+/// Перекидывает кикнутых с сервера игроков на лобби, остальную маршрутизацию оставляет другим плагинам
+pub struct FallbackPlugin {
+	servers: Vec<TargetServer>,
+	/// Подстроки причины кика (без учёта регистра), при которых игрок просто отключается
+	disabling_patterns: Vec<String>,
+}
+impl FallbackPlugin {
+	pub fn new(servers: Vec<TargetServer>) -> Self {
+		Self {
+			servers,
+			disabling_patterns: Vec::new(),
+		}
+	}
+	pub fn disable_on(mut self, pattern: impl Into<String>) -> Self {
+		self.disabling_patterns.push(pattern.into().to_lowercase());
+		self
+	}
+}
+
+#[async_trait]
+impl Plugin for FallbackPlugin {
+	async fn route(&self, ctx: &RouteContext<'_>) -> Option<RouteResult> {
+		match &ctx.reason {
+			RouteReason::Kick { reason, .. } => {
+				let reason = chat::plain_text(reason);
+				let lowercase = reason.to_lowercase();
+				if self
+					.disabling_patterns
+					.iter()
+					.any(|pattern| lowercase.contains(pattern))
+				{
+					return Some(RouteResult::Disconnect(reason));
+				}
+				Some(RouteResult::Prioritized(self.servers.clone()))
+			}
+			_ => None,
+		}
+	}
+}
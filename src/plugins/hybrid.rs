@@ -0,0 +1,278 @@
+use std::{
+	collections::{HashMap, HashSet},
+	net::SocketAddr,
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use reqwest::{Client, StatusCode};
+
+use crate::{
+	plugins::auth::{
+		AuthError, AuthPlugin, AuthSucceeded, AuthlibAuthData, EncryptionStartResult,
+		MojangAuthPlugin, OfflineAuthPlugin,
+	},
+	protocol::login::EncryptionResponse,
+};
+
+pub const MOJANG_PROFILE_API: &str = "https://api.mojang.com/users/profiles/minecraft/";
+
+/// Сколько помнить, что имя не лицензионное: имя могут купить
+const OFFLINE_DECISION_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Есть ли у имени лицензионный аккаунт
+#[async_trait]
+pub trait PremiumLookup: Sync {
+	async fn is_premium(&self, name: &str) -> Result<bool, AuthError>;
+}
+
+/// Заранее известный список лицензионных имён, без учёта регистра
+pub struct PremiumList(HashSet<String>);
+impl PremiumList {
+	pub fn new(names: impl IntoIterator<Item = String>) -> Self {
+		Self(names.into_iter().map(|name| name.to_lowercase()).collect())
+	}
+}
+#[async_trait]
+impl PremiumLookup for PremiumList {
+	async fn is_premium(&self, name: &str) -> Result<bool, AuthError> {
+		Ok(self.0.contains(&name.to_lowercase()))
+	}
+}
+
+/// Спрашивает API профилей: профиль найден - имя лицензионное
+pub struct ProfileApiLookup {
+	client: Client,
+	/// Имя дописывается в конец
+	url: String,
+	timeout: Duration,
+}
+impl ProfileApiLookup {
+	pub fn new(url: impl Into<String>) -> Self {
+		Self {
+			client: Client::new(),
+			url: url.into(),
+			timeout: Duration::from_secs(5),
+		}
+	}
+	pub fn mojang() -> Self {
+		Self::new(MOJANG_PROFILE_API)
+	}
+	/// Не ответившее вовремя API считается ошибкой, вход отклоняется
+	pub fn with_timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = timeout;
+		self
+	}
+}
+#[async_trait]
+impl PremiumLookup for ProfileApiLookup {
+	async fn is_premium(&self, name: &str) -> Result<bool, AuthError> {
+		let response = self
+			.client
+			.get(format!("{}{}", self.url, name))
+			.timeout(self.timeout)
+			.send()
+			.await
+			.map_err(|e| AuthError::Other(e.into()))?;
+		match response.status() {
+			StatusCode::OK => Ok(true),
+			StatusCode::NO_CONTENT | StatusCode::NOT_FOUND => Ok(false),
+			status => Err(AuthError::Other(anyhow::anyhow!(
+				"profile api returned {}",
+				status
+			))),
+		}
+	}
+}
+
+enum Decision {
+	/// Навсегда: лицензионное имя не может стать пиратским
+	Premium,
+	Offline {
+		until: Instant,
+	},
+}
+
+/// Лицензионные аккаунты проходят онлайн авторизацию и получают свой настоящий UUID,
+/// остальные заходят в оффлайн режиме.
+/// Если проверить имя не удалось, вход запрещается, чтобы пират не занял лицензионное имя
+pub struct HybridAuthPlugin<L> {
+	online: MojangAuthPlugin,
+	lookup: L,
+	/// Ключ - имя в нижнем регистре
+	decisions: Mutex<HashMap<String, Decision>>,
+}
+impl<L: PremiumLookup> HybridAuthPlugin<L> {
+	pub fn new(online: MojangAuthPlugin, lookup: L) -> Self {
+		Self {
+			online,
+			lookup,
+			decisions: Mutex::new(HashMap::new()),
+		}
+	}
+
+	async fn is_premium(&self, name: &str) -> Result<bool, AuthError> {
+		let key = name.to_lowercase();
+		match self.decisions.lock().unwrap().get(&key) {
+			Some(Decision::Premium) => return Ok(true),
+			Some(Decision::Offline { until }) if *until > Instant::now() => return Ok(false),
+			_ => {}
+		}
+		let premium = self.lookup.is_premium(name).await?;
+		let decision = if premium {
+			Decision::Premium
+		} else {
+			Decision::Offline {
+				until: Instant::now() + OFFLINE_DECISION_TTL,
+			}
+		};
+		self.decisions.lock().unwrap().insert(key, decision);
+		Ok(premium)
+	}
+}
+
+#[async_trait]
+impl<L: PremiumLookup> AuthPlugin for HybridAuthPlugin<L> {
+	type AuthData = AuthlibAuthData;
+	async fn encryption_start(
+		&self,
+		name: String,
+	) -> Result<EncryptionStartResult<Self::AuthData>, AuthError> {
+		if self.is_premium(&name).await? {
+			return self.online.encryption_start(name).await;
+		}
+		match OfflineAuthPlugin.encryption_start(name).await? {
+			EncryptionStartResult::Skip(success) => Ok(EncryptionStartResult::Skip(success)),
+			EncryptionStartResult::BeginEncryption(_, never) => match never {},
+		}
+	}
+	async fn encryption_response(
+		&self,
+		data: Self::AuthData,
+		res: EncryptionResponse,
+		client_addr: SocketAddr,
+	) -> Result<AuthSucceeded, AuthError> {
+		let success = self
+			.online
+			.encryption_response(data, res, client_addr)
+			.await?;
+		self.decisions
+			.lock()
+			.unwrap()
+			.insert(success.username.to_lowercase(), Decision::Premium);
+		Ok(success)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::plugins::test_http;
+	use std::sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc,
+	};
+
+	/// API профилей, отвечающее кодами по порядку, последний повторяется.
+	/// Возвращает адрес и счётчик запросов
+	async fn profile_api(statuses: Vec<&'static str>) -> (String, Arc<AtomicUsize>) {
+		let (addr, requests) = test_http::serve(move |number, _| {
+			let status = statuses[number.min(statuses.len() - 1)];
+			let body = if status.starts_with("200") {
+				r#"{"id":"069a79f444e94726a5befca90e38aaf5","name":"Notch"}"#
+			} else {
+				""
+			};
+			test_http::response(status, body)
+		})
+		.await;
+		(
+			format!("http://{}/users/profiles/minecraft/", addr),
+			requests,
+		)
+	}
+
+	fn hybrid<L: PremiumLookup>(lookup: L) -> HybridAuthPlugin<L> {
+		HybridAuthPlugin::new(MojangAuthPlugin::with_generated_keypair(), lookup)
+	}
+
+	/// true - онлайн вход с шифрованием, false - оффлайн
+	async fn is_online<L: PremiumLookup>(
+		plugin: &HybridAuthPlugin<L>,
+		name: &str,
+	) -> Result<bool, AuthError> {
+		match plugin.encryption_start(name.to_owned()).await? {
+			EncryptionStartResult::BeginEncryption(..) => Ok(true),
+			EncryptionStartResult::Skip(success) => {
+				assert_eq!(success.username, name);
+				Ok(false)
+			}
+		}
+	}
+
+	#[tokio::test]
+	async fn premium_list() {
+		let plugin = hybrid(PremiumList::new(vec!["Notch".to_owned()]));
+		assert!(is_online(&plugin, "notch").await.unwrap());
+		assert!(!is_online(&plugin, "Steve").await.unwrap());
+	}
+
+	#[tokio::test]
+	async fn profile_api_decides_mode() {
+		let (url, _) = profile_api(vec!["200 OK"]).await;
+		assert!(is_online(&hybrid(ProfileApiLookup::new(url)), "Notch")
+			.await
+			.unwrap());
+		for status in ["204 No Content", "404 Not Found"] {
+			let (url, _) = profile_api(vec![status]).await;
+			assert!(!is_online(&hybrid(ProfileApiLookup::new(url)), "Steve")
+				.await
+				.unwrap());
+		}
+	}
+
+	#[tokio::test]
+	async fn profile_api_errors_refuse_login() {
+		let (url, _) = profile_api(vec!["500 Internal Server Error"]).await;
+		assert!(is_online(&hybrid(ProfileApiLookup::new(url)), "Notch")
+			.await
+			.is_err());
+	}
+
+	#[tokio::test]
+	async fn profile_api_timeout_refuses_login() {
+		let url = format!("http://{}/", test_http::silent().await);
+		let lookup = ProfileApiLookup::new(url).with_timeout(Duration::from_millis(200));
+		let result =
+			tokio::time::timeout(Duration::from_secs(5), is_online(&hybrid(lookup), "Notch"))
+				.await
+				.expect("request timeout must fire first");
+		assert!(result.is_err());
+	}
+
+	#[tokio::test]
+	async fn premium_name_never_falls_back_to_offline() {
+		let (url, requests) = profile_api(vec![
+			"200 OK",
+			"500 Internal Server Error",
+			"204 No Content",
+		])
+		.await;
+		let plugin = hybrid(ProfileApiLookup::new(url));
+		assert!(is_online(&plugin, "Notch").await.unwrap());
+		// API лежит или забыло имя - решение уже запомнено
+		assert!(is_online(&plugin, "NOTCH").await.unwrap());
+		assert!(is_online(&plugin, "Notch").await.unwrap());
+		assert_eq!(requests.load(Ordering::SeqCst), 1);
+	}
+
+	#[tokio::test]
+	async fn offline_decision_is_cached() {
+		let (url, requests) = profile_api(vec!["204 No Content"]).await;
+		let plugin = hybrid(ProfileApiLookup::new(url));
+		assert!(!is_online(&plugin, "Steve").await.unwrap());
+		assert!(!is_online(&plugin, "steve").await.unwrap());
+		assert_eq!(requests.load(Ordering::SeqCst), 1);
+	}
+}
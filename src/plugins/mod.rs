@@ -1 +1,7 @@
-pub mod auth;
\ No newline at end of file
+pub mod access;
+pub mod auth;
+pub mod fallback;
+pub mod hybrid;
+pub mod password;
+#[cfg(test)]
+mod test_http;
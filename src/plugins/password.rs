@@ -0,0 +1,233 @@
+use std::{
+	collections::{hash_map::Entry, HashMap},
+	io,
+	path::PathBuf,
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+
+use argon2::{Config, Variant};
+use async_trait::async_trait;
+use rand::{thread_rng, Rng};
+use thiserror::Error;
+use tokio::{task::spawn_blocking, time::timeout};
+use uuid::Uuid;
+
+use crate::{
+	json_file::JsonFile,
+	limbo::{Limbo, LimboError},
+	plugin::Plugin,
+};
+
+const MIN_PASSWORD_LENGTH: usize = 6;
+
+#[derive(Debug, Error)]
+pub enum PasswordDbError {
+	#[error("io error: {0}")]
+	Io(#[from] io::Error),
+	#[error("bad password database: {0}")]
+	Json(#[from] serde_json::Error),
+	#[error("hashing error: {0}")]
+	Argon2(#[from] argon2::Error),
+}
+
+struct Lockout {
+	failures: u32,
+	until: Option<Instant>,
+}
+
+/// Держит оффлайн игроков в лимбо, пока они не войдут (`/login`) или не зарегистрируются (`/register`).
+/// Игроки с лицензионным UUID проходят без пароля
+pub struct PasswordLogin {
+	/// UUID -> argon2 хеш пароля
+	accounts: JsonFile<HashMap<String, String>>,
+	/// UUID -> неудачные попытки входа
+	lockouts: Mutex<HashMap<String, Lockout>>,
+	max_attempts: u32,
+	lockout: Duration,
+	login_timeout: Duration,
+}
+impl PasswordLogin {
+	/// Если файла нет, он будет создан при первой регистрации
+	pub fn load(path: impl Into<PathBuf>) -> Result<Self, PasswordDbError> {
+		JsonFile::load(path).map(|accounts| Self {
+			accounts,
+			lockouts: Mutex::new(HashMap::new()),
+			max_attempts: 5,
+			lockout: Duration::from_secs(5 * 60),
+			login_timeout: Duration::from_secs(60),
+		})
+	}
+	/// После `max_attempts` неверных паролей подряд вход блокируется на `duration`
+	pub fn with_lockout(mut self, max_attempts: u32, duration: Duration) -> Self {
+		self.max_attempts = max_attempts;
+		self.lockout = duration;
+		self
+	}
+	/// Сколько игрок может пробыть в лимбо, не войдя
+	pub fn with_login_timeout(mut self, login_timeout: Duration) -> Self {
+		self.login_timeout = login_timeout;
+		self
+	}
+
+	fn is_registered(&self, uuid: &str) -> bool {
+		self.accounts.read().contains_key(uuid)
+	}
+
+	/// false - аккаунт уже зарегистрирован, например из другого соединения, пока считался хеш
+	async fn register(&self, uuid: &str, password: String) -> Result<bool, PasswordDbError> {
+		let salt = thread_rng().gen::<[u8; 16]>();
+		let hash = spawn_blocking(move || {
+			let config = Config {
+				variant: Variant::Argon2id,
+				..Config::default()
+			};
+			argon2::hash_encoded(password.as_bytes(), &salt, &config)
+		})
+		.await
+		.map_err(io::Error::other)??;
+		if self.is_registered(uuid) {
+			return Ok(false);
+		}
+		// Проверка повторяется под блокировкой, второй регистрации из другого соединения не будет
+		self.accounts
+			.update(|accounts| match accounts.entry(uuid.to_owned()) {
+				Entry::Occupied(_) => false,
+				Entry::Vacant(entry) => {
+					entry.insert(hash);
+					true
+				}
+			})
+	}
+
+	async fn verify(&self, uuid: &str, password: String) -> Result<bool, PasswordDbError> {
+		let hash = match self.accounts.read().get(uuid) {
+			Some(hash) => hash.clone(),
+			None => return Ok(false),
+		};
+		Ok(
+			spawn_blocking(move || argon2::verify_encoded(&hash, password.as_bytes()))
+				.await
+				.map_err(io::Error::other)??,
+		)
+	}
+
+	/// Сколько ещё ждать до снятия блокировки
+	fn locked_for(&self, uuid: &str) -> Option<Duration> {
+		let mut lockouts = self.lockouts.lock().unwrap();
+		let until = lockouts.get(uuid)?.until?;
+		let now = Instant::now();
+		if until > now {
+			return Some(until - now);
+		}
+		lockouts.remove(uuid);
+		None
+	}
+	/// Возвращает, сколько попыток осталось
+	fn record_failure(&self, uuid: &str) -> u32 {
+		let mut lockouts = self.lockouts.lock().unwrap();
+		let lockout = lockouts.entry(uuid.to_owned()).or_insert(Lockout {
+			failures: 0,
+			until: None,
+		});
+		lockout.failures += 1;
+		if lockout.failures >= self.max_attempts {
+			lockout.failures = 0;
+			lockout.until = Some(Instant::now() + self.lockout);
+			return 0;
+		}
+		self.max_attempts - lockout.failures
+	}
+
+	async fn authenticate(&self, limbo: &mut Limbo<'_>, uuid: &str) -> Result<(), LimboError> {
+		let mut registered = self.is_registered(uuid);
+		loop {
+			if let Some(left) = self.locked_for(uuid) {
+				return Err(locked(left));
+			}
+			limbo
+				.message(if registered {
+					"Please log in: /login <password>"
+				} else {
+					"Please register: /register <password> <password>"
+				})
+				.await?;
+			let message = limbo.next_message().await?;
+			let words = message.split_whitespace().collect::<Vec<_>>();
+			match (registered, &words[..]) {
+				(true, ["/login", password]) => {
+					if self
+						.verify(uuid, password.to_string())
+						.await
+						.map_err(db_error)?
+					{
+						self.lockouts.lock().unwrap().remove(uuid);
+						limbo.message("Logged in").await?;
+						return Ok(());
+					}
+					match self.record_failure(uuid) {
+						0 => return Err(locked(self.lockout)),
+						left => {
+							limbo
+								.message(&format!("Wrong password, {} attempts left", left))
+								.await?
+						}
+					}
+				}
+				(false, ["/register", password, repeat]) => {
+					if password != repeat {
+						limbo.message("Passwords don't match").await?;
+					} else if password.chars().count() < MIN_PASSWORD_LENGTH {
+						limbo
+							.message(&format!(
+								"Password must be at least {} characters long",
+								MIN_PASSWORD_LENGTH
+							))
+							.await?;
+					} else if self
+						.register(uuid, password.to_string())
+						.await
+						.map_err(db_error)?
+					{
+						limbo.message("Registered").await?;
+						return Ok(());
+					} else {
+						limbo
+							.message("Already registered, use /login <password>")
+							.await?;
+					}
+				}
+				_ => {}
+			}
+			registered = self.is_registered(uuid);
+		}
+	}
+}
+
+fn locked(left: Duration) -> LimboError {
+	LimboError::disconnect(&format!(
+		"Too many failed login attempts, try again in {} minutes",
+		left.as_secs().div_ceil(60)
+	))
+}
+
+fn db_error(e: PasswordDbError) -> LimboError {
+	println!("Password database error: {}", e);
+	LimboError::disconnect("Login is temporarily unavailable")
+}
+
+#[async_trait]
+impl Plugin for PasswordLogin {
+	async fn limbo(&self, limbo: &mut Limbo<'_>) -> Result<(), LimboError> {
+		let uuid = limbo.info().uuid.clone();
+		// Оффлайн UUID считается от имени (версия 3), у лицензионных аккаунтов версия 4
+		let offline = matches!(Uuid::parse_str(&uuid), Ok(uuid) if uuid.get_version_num() == 3);
+		if !offline {
+			return Ok(());
+		}
+		match timeout(self.login_timeout, self.authenticate(limbo, &uuid)).await {
+			Ok(result) => result,
+			Err(_) => Err(LimboError::disconnect("Login timed out")),
+		}
+	}
+}
@@ -0,0 +1,75 @@
+//! Простейший HTTP сервер для тестов плагинов, которые ходят во внешние API
+
+use std::{
+	net::SocketAddr,
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc,
+	},
+};
+
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::TcpListener,
+};
+
+/// На каждый запрос вызывает `respond` с номером запроса (с нуля) и путём.
+/// Возвращает адрес и счётчик запросов
+pub async fn serve(
+	respond: impl Fn(usize, &str) -> String + Send + 'static,
+) -> (SocketAddr, Arc<AtomicUsize>) {
+	let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let addr = listener.local_addr().unwrap();
+	let requests = Arc::new(AtomicUsize::new(0));
+	let counter = requests.clone();
+	tokio::spawn(async move {
+		loop {
+			let (mut stream, _) = listener.accept().await.unwrap();
+			let mut request = Vec::new();
+			let mut buf = [0; 1024];
+			while !request.ends_with(b"\r\n\r\n") {
+				let read = stream.read(&mut buf).await.unwrap();
+				if read == 0 {
+					break;
+				}
+				request.extend_from_slice(&buf[..read]);
+			}
+			let request = String::from_utf8_lossy(&request);
+			let path = request.split(' ').nth(1).unwrap_or_default();
+			let number = counter.fetch_add(1, Ordering::SeqCst);
+			let response = respond(number, path);
+			stream.write_all(response.as_bytes()).await.unwrap();
+			stream.shutdown().await.unwrap();
+		}
+	});
+	(addr, requests)
+}
+
+/// Ответ с кодом вроде `"200 OK"`, пустое тело - без Content-Type
+pub fn response(status: &str, body: &str) -> String {
+	let content_type = if body.is_empty() {
+		""
+	} else {
+		"Content-Type: application/json\r\n"
+	};
+	format!(
+		"HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+		status,
+		content_type,
+		body.len(),
+		body
+	)
+}
+
+/// Принимает соединения и ничего не отвечает
+pub async fn silent() -> SocketAddr {
+	let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let addr = listener.local_addr().unwrap();
+	tokio::spawn(async move {
+		let mut streams = Vec::new();
+		loop {
+			streams.push(listener.accept().await.unwrap());
+		}
+	});
+	addr
+}
@@ -47,6 +47,39 @@ impl PacketData for bool {
 		Ok(())
 	}
 }
+impl PacketData for i8 {
+	fn read<R: Read>(buf: &mut R) -> io::Result<Self> {
+		buf.read_i8()
+	}
+	fn write<W: Write>(&self, buf: &mut W) -> io::Result<()> {
+		buf.write_i8(*self)
+	}
+}
+impl PacketData for f32 {
+	fn read<R: Read>(buf: &mut R) -> io::Result<Self> {
+		buf.read_f32::<BigEndian>()
+	}
+	fn write<W: Write>(&self, buf: &mut W) -> io::Result<()> {
+		buf.write_f32::<BigEndian>(*self)
+	}
+}
+impl PacketData for f64 {
+	fn read<R: Read>(buf: &mut R) -> io::Result<Self> {
+		buf.read_f64::<BigEndian>()
+	}
+	fn write<W: Write>(&self, buf: &mut W) -> io::Result<()> {
+		buf.write_f64::<BigEndian>(*self)
+	}
+}
+/// UUID
+impl PacketData for u128 {
+	fn read<R: Read>(buf: &mut R) -> io::Result<Self> {
+		buf.read_u128::<BigEndian>()
+	}
+	fn write<W: Write>(&self, buf: &mut W) -> io::Result<()> {
+		buf.write_u128::<BigEndian>(*self)
+	}
+}
 impl PacketData for u64 {
 	fn read<R: Read>(buf: &mut R) -> io::Result<Self> {
 		Ok(buf.read_i64::<BigEndian>()? as u64)
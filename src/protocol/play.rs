@@ -1,6 +1,7 @@
 use super::*;
+use std::io::Read;
 
-#[derive(PacketData)]
+#[derive(Debug, PacketData)]
 pub struct JoinGame {
 	pub entity_id: i32,
 	pub game_mode: u8,
@@ -32,10 +33,207 @@ impl Packet for ChatResponse {
 	const ID: i32 = 0x0F;
 }
 
+/// Клиентский пакет, клиент отвечает таким же со своим ID
 #[derive(Debug, PacketData)]
 pub struct KeepAlive {
-	pub random_id: i16,
+	pub random_id: i64,
 }
 impl Packet for KeepAlive {
-	const ID: i32 = 0x21;
+	const ID: i32 = 0x1F;
+}
+
+#[derive(Debug, PacketData)]
+pub struct Disconnect {
+	pub reason: String,
+}
+impl Packet for Disconnect {
+	const ID: i32 = 0x1A;
+}
+
+#[derive(Debug, PacketData)]
+pub struct Respawn {
+	pub dimension: i32,
+	pub difficulty: u8,
+	pub game_mode: u8,
+	pub level_type: String,
+}
+impl Packet for Respawn {
+	const ID: i32 = 0x35;
+}
+
+#[derive(Debug, PacketData)]
+pub struct ChangeGameState {
+	pub reason: u8,
+	pub value: f32,
+}
+impl Packet for ChangeGameState {
+	const ID: i32 = 0x1E;
+}
+impl ChangeGameState {
+	/// На wiki.vg значения 1 и 2 перепутаны, клиент выключает дождь именно на 2
+	pub const END_RAINING: u8 = 2;
+	pub const RAIN_LEVEL: u8 = 7;
+	pub const THUNDER_LEVEL: u8 = 8;
+}
+
+/// Только имя и действие, остальные поля зависят от действия и не нужны для удаления
+#[derive(Debug, PacketData)]
+pub struct ScoreboardObjective {
+	pub name: String,
+	pub mode: i8,
+}
+impl Packet for ScoreboardObjective {
+	const ID: i32 = 0x42;
+}
+
+/// Только имя и действие, остальные поля зависят от действия и не нужны для удаления
+#[derive(Debug, PacketData)]
+pub struct Teams {
+	pub name: String,
+	pub mode: i8,
+}
+impl Packet for Teams {
+	const ID: i32 = 0x44;
+}
+
+/// Только UUID и действие, остальные поля зависят от действия и не нужны для удаления
+#[derive(Debug, PacketData)]
+pub struct BossBar {
+	pub uuid: u128,
+	pub action: VarInt,
+}
+impl Packet for BossBar {
+	const ID: i32 = 0x0C;
+}
+
+/// Только UUID затронутых игроков, данные действий при чтении пропускаются,
+/// поэтому записывать имеет смысл лишь действие удаления
+#[derive(Debug)]
+pub struct PlayerListItem {
+	pub action: VarInt,
+	pub players: Vec<u128>,
+}
+impl PlayerListItem {
+	pub const ADD_PLAYER: i32 = 0;
+	pub const REMOVE_PLAYER: i32 = 4;
+}
+impl Packet for PlayerListItem {
+	const ID: i32 = 0x2E;
+}
+impl PacketData for PlayerListItem {
+	fn read<R: Read>(buf: &mut R) -> io::Result<Self> {
+		let action = VarInt::read(buf)?;
+		let count = VarInt::read(buf)?;
+		// Число записей приходит от сервера, заранее выделяем не больше разумного
+		let mut players = Vec::with_capacity(count.0.clamp(0, 1024) as usize);
+		for _ in 0..count.0 {
+			players.push(u128::read(buf)?);
+			match action.0 {
+				Self::ADD_PLAYER => {
+					String::read(buf)?;
+					let properties = VarInt::read(buf)?;
+					for _ in 0..properties.0 {
+						String::read(buf)?;
+						String::read(buf)?;
+						if bool::read(buf)? {
+							String::read(buf)?;
+						}
+					}
+					VarInt::read(buf)?;
+					VarInt::read(buf)?;
+					if bool::read(buf)? {
+						String::read(buf)?;
+					}
+				}
+				// Update gamemode/latency
+				1 | 2 => {
+					VarInt::read(buf)?;
+				}
+				// Update display name
+				3 if bool::read(buf)? => {
+					String::read(buf)?;
+				}
+				_ => {}
+			}
+		}
+		Ok(Self { action, players })
+	}
+	fn write<W: std::io::Write>(&self, buf: &mut W) -> io::Result<()> {
+		self.action.write(buf)?;
+		self.players.write(buf)
+	}
+}
+
+/// Только действия с текстом: заголовок, подзаголовок и сообщение над хотбаром
+#[derive(Debug, PacketData)]
+pub struct Title {
+	pub action: VarInt,
+	pub text: String,
+}
+impl Title {
+	pub const SET_TITLE: i32 = 0;
+	pub const SET_SUBTITLE: i32 = 1;
+}
+impl Packet for Title {
+	const ID: i32 = 0x48;
+}
+
+#[derive(Debug)]
+pub struct TabCompleteRequest {
+	pub text: String,
+	pub assume_command: bool,
+	/// Блок, на который смотрит игрок
+	pub looked_at_block: Option<u64>,
+}
+impl Packet for TabCompleteRequest {
+	const ID: i32 = 0x01;
+}
+impl PacketData for TabCompleteRequest {
+	fn read<R: Read>(buf: &mut R) -> io::Result<Self> {
+		let text = String::read(buf)?;
+		let assume_command = bool::read(buf)?;
+		let looked_at_block = if bool::read(buf)? {
+			Some(u64::read(buf)?)
+		} else {
+			None
+		};
+		Ok(Self {
+			text,
+			assume_command,
+			looked_at_block,
+		})
+	}
+	fn write<W: std::io::Write>(&self, buf: &mut W) -> io::Result<()> {
+		self.text.write(buf)?;
+		self.assume_command.write(buf)?;
+		self.looked_at_block.is_some().write(buf)?;
+		if let Some(position) = self.looked_at_block {
+			position.write(buf)?;
+		}
+		Ok(())
+	}
+}
+
+/// Варианты заменяют последнее слово введённого текста
+#[derive(Debug, PacketData)]
+pub struct TabCompleteResponse {
+	pub matches: Vec<String>,
+}
+impl Packet for TabCompleteResponse {
+	const ID: i32 = 0x0E;
+}
+
+#[derive(Debug, PacketData)]
+pub struct PlayerPositionAndLook {
+	pub x: f64,
+	pub y: f64,
+	pub z: f64,
+	pub yaw: f32,
+	pub pitch: f32,
+	/// Битовая маска относительных координат
+	pub flags: i8,
+	pub teleport_id: VarInt,
+}
+impl Packet for PlayerPositionAndLook {
+	const ID: i32 = 0x2F;
 }
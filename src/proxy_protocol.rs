@@ -0,0 +1,219 @@
+//! Заголовок PROXY protocol, которым балансировщик (HAProxy, nginx) сообщает настоящий адрес клиента.
+//! https://www.haproxy.org/download/2.4/doc/proxy-protocol.txt
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{self, AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Максимальная длина строки v1 вместе с \r\n
+const V1_MAX_LENGTH: usize = 107;
+
+fn invalid(message: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// Читает заголовок целиком, не трогая данные после него.
+/// None - адрес не передан (`UNKNOWN` в v1, `LOCAL` в v2), нужно использовать адрес соединения
+pub async fn read_header(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<SocketAddr>> {
+	// Самый короткий заголовок v1 ("PROXY UNKNOWN\r\n") длиннее подписи v2
+	let mut start = [0; 12];
+	stream.read_exact(&mut start).await?;
+	if start == V2_SIGNATURE {
+		read_v2(stream).await
+	} else if start.starts_with(b"PROXY ") {
+		read_v1(stream, &start).await
+	} else {
+		Err(invalid("missing proxy protocol header"))
+	}
+}
+
+async fn read_v1(
+	stream: &mut (impl AsyncRead + Unpin),
+	start: &[u8],
+) -> io::Result<Option<SocketAddr>> {
+	let mut line = start.to_vec();
+	while !line.ends_with(b"\r\n") {
+		if line.len() >= V1_MAX_LENGTH {
+			return Err(invalid("proxy protocol v1 header is too long"));
+		}
+		line.push(stream.read_u8().await?);
+	}
+	let line = std::str::from_utf8(&line[..line.len() - 2])
+		.map_err(|_| invalid("proxy protocol v1 header is not ascii"))?;
+	// PROXY TCP4 <src ip> <dst ip> <src port> <dst port>
+	let parts = line.split(' ').collect::<Vec<_>>();
+	match &parts[..] {
+		["PROXY", "UNKNOWN", ..] => Ok(None),
+		["PROXY", "TCP4", ip, _, port, _] | ["PROXY", "TCP6", ip, _, port, _] => {
+			let ip = ip
+				.parse::<IpAddr>()
+				.map_err(|_| invalid("bad source address in proxy protocol v1 header"))?;
+			let port = port
+				.parse::<u16>()
+				.map_err(|_| invalid("bad source port in proxy protocol v1 header"))?;
+			Ok(Some(SocketAddr::new(ip, port)))
+		}
+		_ => Err(invalid("bad proxy protocol v1 header")),
+	}
+}
+
+async fn read_v2(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<SocketAddr>> {
+	let version_command = stream.read_u8().await?;
+	let family = stream.read_u8().await?;
+	let length = stream.read_u16().await? as usize;
+	let mut data = vec![0; length];
+	stream.read_exact(&mut data).await?;
+	if version_command >> 4 != 2 {
+		return Err(invalid("unsupported proxy protocol version"));
+	}
+	match version_command & 0x0f {
+		// LOCAL - проверка здоровья от самого балансировщика
+		0 => return Ok(None),
+		// PROXY
+		1 => {}
+		_ => return Err(invalid("unsupported proxy protocol command")),
+	}
+	match family >> 4 {
+		// AF_INET: src ip, dst ip, src port, dst port
+		1 if data.len() >= 12 => {
+			let mut ip = [0; 4];
+			ip.copy_from_slice(&data[0..4]);
+			let port = u16::from_be_bytes([data[8], data[9]]);
+			Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+		}
+		// AF_INET6
+		2 if data.len() >= 36 => {
+			let mut ip = [0; 16];
+			ip.copy_from_slice(&data[0..16]);
+			let port = u16::from_be_bytes([data[32], data[33]]);
+			Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+		}
+		// AF_UNSPEC и unix сокеты адреса клиента не несут
+		0 | 3 => Ok(None),
+		_ => Err(invalid("bad proxy protocol v2 address block")),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Возвращает адрес и то, что осталось непрочитанным после заголовка
+	async fn parse(mut data: &[u8]) -> io::Result<(Option<SocketAddr>, &[u8])> {
+		let addr = read_header(&mut data).await?;
+		Ok((addr, data))
+	}
+
+	fn v2(command: u8, family: u8, address: &[u8]) -> Vec<u8> {
+		let mut data = V2_SIGNATURE.to_vec();
+		data.push(0x20 | command);
+		data.push(family);
+		data.extend_from_slice(&(address.len() as u16).to_be_bytes());
+		data.extend_from_slice(address);
+		data.extend_from_slice(b"rest");
+		data
+	}
+
+	#[tokio::test]
+	async fn v1_tcp4() {
+		let (addr, rest) = parse(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 25565\r\nrest")
+			.await
+			.unwrap();
+		assert_eq!(addr, Some("203.0.113.7:51234".parse().unwrap()));
+		assert_eq!(rest, b"rest");
+	}
+
+	#[tokio::test]
+	async fn v1_tcp6() {
+		let (addr, rest) = parse(b"PROXY TCP6 2001:db8::7 2001:db8::1 51234 25565\r\nrest")
+			.await
+			.unwrap();
+		assert_eq!(addr, Some("[2001:db8::7]:51234".parse().unwrap()));
+		assert_eq!(rest, b"rest");
+	}
+
+	#[tokio::test]
+	async fn v1_unknown() {
+		let (addr, rest) = parse(b"PROXY UNKNOWN\r\nrest").await.unwrap();
+		assert_eq!(addr, None);
+		assert_eq!(rest, b"rest");
+	}
+
+	#[tokio::test]
+	async fn v1_too_long() {
+		let mut line = b"PROXY TCP4 ".to_vec();
+		line.extend_from_slice(&[b'1'; 200]);
+		line.extend_from_slice(b"\r\n");
+		assert!(parse(&line).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn v1_malformed() {
+		assert!(parse(b"PROXY TCP4 not-an-ip 10.0.0.1 1 2\r\n")
+			.await
+			.is_err());
+		assert!(parse(b"PROXY TCP4 203.0.113.7 10.0.0.1 99999 2\r\n")
+			.await
+			.is_err());
+		assert!(parse(b"PROXY TCP5 203.0.113.7 10.0.0.1 1 2\r\n")
+			.await
+			.is_err());
+	}
+
+	#[tokio::test]
+	async fn bad_signature() {
+		// Обычный хендшейк Minecraft без заголовка
+		assert!(
+			parse(&[0x10, 0x00, 0xd4, 0x02, 0x09, b'l', b'o', b'c', b'a', b'l', b'h', b'o'])
+				.await
+				.is_err()
+		);
+		assert!(parse(b"\r\n\r\n\0\r\nQUIX\n\x21\x11\0\0").await.is_err());
+	}
+
+	#[tokio::test]
+	async fn v2_local() {
+		let data = v2(0, 0x11, &[0; 12]);
+		let (addr, rest) = parse(&data).await.unwrap();
+		assert_eq!(addr, None);
+		assert_eq!(rest, b"rest");
+	}
+
+	#[tokio::test]
+	async fn v2_inet() {
+		let data = v2(
+			1,
+			0x11,
+			&[203, 0, 113, 7, 10, 0, 0, 1, 0xc8, 0x22, 0x63, 0xdd],
+		);
+		let (addr, rest) = parse(&data).await.unwrap();
+		assert_eq!(addr, Some("203.0.113.7:51234".parse().unwrap()));
+		assert_eq!(rest, b"rest");
+	}
+
+	#[tokio::test]
+	async fn v2_inet6() {
+		let mut address = Vec::new();
+		address.extend_from_slice(&"2001:db8::7".parse::<Ipv6Addr>().unwrap().octets());
+		address.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+		address.extend_from_slice(&51234u16.to_be_bytes());
+		address.extend_from_slice(&25565u16.to_be_bytes());
+		let data = v2(1, 0x21, &address);
+		let (addr, rest) = parse(&data).await.unwrap();
+		assert_eq!(addr, Some("[2001:db8::7]:51234".parse().unwrap()));
+		assert_eq!(rest, b"rest");
+	}
+
+	#[tokio::test]
+	async fn v2_short_address_block() {
+		assert!(parse(&v2(1, 0x11, &[203, 0, 113, 7])).await.is_err());
+		assert!(parse(&v2(1, 0x21, &[0; 12])).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn v2_unknown_command() {
+		assert!(parse(&v2(2, 0x11, &[0; 12])).await.is_err());
+		assert!(parse(&v2(0xf, 0x11, &[0; 12])).await.is_err());
+	}
+}
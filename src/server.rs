@@ -0,0 +1,428 @@
+use std::{
+	collections::{HashMap, HashSet},
+	net::SocketAddr,
+	sync::{Arc, Mutex, RwLock},
+	time::{Duration, Instant},
+};
+
+use crate::{
+	command::{register_builtins, Commands},
+	event::EventBus,
+	intercept::PacketHooks,
+	permissions::Permissions,
+	plugin::TargetServer,
+	plugins::auth::ProfileProperty,
+	session::SessionHandle,
+	LoggedInInfo,
+};
+
+/// Игрок, прошедший авторизацию
+pub struct ConnectedPlayer {
+	pub username: String,
+	pub uuid: String,
+	pub protocol: i32,
+	pub address: SocketAddr,
+	pub connected_at: Instant,
+	/// Подписанные свойства профиля (скин), пусто в оффлайн режиме
+	pub properties: Vec<ProfileProperty>,
+	server: Mutex<Option<TargetServer>>,
+	handle: SessionHandle,
+}
+impl ConnectedPlayer {
+	pub fn new(info: &LoggedInInfo, address: SocketAddr, handle: SessionHandle) -> Self {
+		Self {
+			username: info.username.clone(),
+			uuid: info.uuid.clone(),
+			protocol: info.protocol,
+			address,
+			connected_at: Instant::now(),
+			properties: info.properties.clone(),
+			server: Mutex::new(None),
+			handle,
+		}
+	}
+	/// Сервер, на котором сейчас играет игрок
+	pub fn server(&self) -> Option<TargetServer> {
+		self.server.lock().unwrap().clone()
+	}
+	pub(crate) fn set_server(&self, server: Option<TargetServer>) {
+		*self.server.lock().unwrap() = server;
+	}
+	pub fn handle(&self) -> &SessionHandle {
+		&self.handle
+	}
+}
+
+#[derive(Default)]
+struct Players {
+	by_uuid: HashMap<String, Arc<ConnectedPlayer>>,
+	/// Ключ - имя в нижнем регистре
+	by_name: HashMap<String, Arc<ConnectedPlayer>>,
+}
+impl Players {
+	fn remove(&mut self, player: &Arc<ConnectedPlayer>) {
+		let name = player.username.to_lowercase();
+		if matches!(self.by_name.get(&name), Some(p) if Arc::ptr_eq(p, player)) {
+			self.by_name.remove(&name);
+		}
+		if matches!(self.by_uuid.get(&player.uuid), Some(p) if Arc::ptr_eq(p, player)) {
+			self.by_uuid.remove(&player.uuid);
+		}
+	}
+}
+
+/// Что делать, если игрок с таким же именем или UUID уже на прокси
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DuplicateLoginPolicy {
+	/// Кикнуть уже подключенного игрока
+	#[default]
+	KickExisting,
+	/// Не пускать нового игрока
+	RefuseNew,
+}
+/// Игрок с таким же именем или UUID уже на прокси, а политика - [`DuplicateLoginPolicy::RefuseNew`]
+#[derive(Debug)]
+pub struct AlreadyConnected;
+
+/// Как сообщать серверам настоящие IP, UUID и свойства профиля игрока
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Forwarding {
+	/// Сервер видит IP прокси и оффлайн UUID
+	#[default]
+	None,
+	/// Данные дописываются к адресу в хендшейке, на сервере нужен `bungeecord: true`
+	BungeeCord,
+}
+
+/// Сколько даётся на вход, включая авторизацию на сервере сессий
+pub const DEFAULT_LOGIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Какие имена принимаются в LoginStart. По умолчанию как в ванилле: 3-16 символов из `[A-Za-z0-9_]`
+#[derive(Clone, Debug)]
+pub struct UsernameRules {
+	pub min_length: usize,
+	pub max_length: usize,
+	/// Символы, разрешённые помимо латиницы, цифр и `_`
+	pub extra_chars: String,
+}
+impl Default for UsernameRules {
+	fn default() -> Self {
+		Self {
+			min_length: 3,
+			max_length: 16,
+			extra_chars: String::new(),
+		}
+	}
+}
+impl UsernameRules {
+	pub fn is_valid(&self, name: &str) -> bool {
+		let length = name.chars().count();
+		length >= self.min_length
+			&& length <= self.max_length
+			&& name
+				.chars()
+				.all(|c| c.is_ascii_alphanumeric() || c == '_' || self.extra_chars.contains(c))
+	}
+}
+
+/// Общее состояние прокси, клонирование дёшево
+#[derive(Clone, Default)]
+pub struct ProxyServer {
+	players: Arc<RwLock<Players>>,
+	events: Arc<EventBus>,
+	packets: Arc<PacketHooks>,
+	commands: Arc<Commands>,
+	permissions: Arc<Permissions>,
+	duplicate_login: DuplicateLoginPolicy,
+	forwarding: Forwarding,
+	proxy_protocol: bool,
+	username_rules: Arc<UsernameRules>,
+	login_timeout: Option<Duration>,
+	/// Адреса из хендшейка в нижнем регистре
+	restricted_servers: Arc<HashSet<String>>,
+}
+impl ProxyServer {
+	pub fn new() -> Self {
+		let proxy = Self::default();
+		register_builtins(&proxy.commands);
+		proxy
+	}
+	pub fn with_duplicate_login(mut self, policy: DuplicateLoginPolicy) -> Self {
+		self.duplicate_login = policy;
+		self
+	}
+	pub fn with_permissions(mut self, permissions: Permissions) -> Self {
+		self.permissions = Arc::new(permissions);
+		self
+	}
+	pub fn with_forwarding(mut self, forwarding: Forwarding) -> Self {
+		self.forwarding = forwarding;
+		self
+	}
+	/// Каждое соединение начинается с заголовка PROXY protocol (v1 или v2) от балансировщика,
+	/// адрес игрока берётся из него. Соединения без заголовка отклоняются
+	pub fn with_proxy_protocol(mut self, enabled: bool) -> Self {
+		self.proxy_protocol = enabled;
+		self
+	}
+	pub fn with_username_rules(mut self, rules: UsernameRules) -> Self {
+		self.username_rules = Arc::new(rules);
+		self
+	}
+	/// Игрок, не успевший войти за это время, отключается, по умолчанию [`DEFAULT_LOGIN_TIMEOUT`]
+	pub fn with_login_timeout(mut self, timeout: Duration) -> Self {
+		self.login_timeout = Some(timeout);
+		self
+	}
+	/// На эти серверы (по адресу из хендшейка) пускаются только игроки с правом `proxy.server.<адрес>`
+	pub fn with_restricted_servers(mut self, servers: impl IntoIterator<Item = String>) -> Self {
+		self.restricted_servers = Arc::new(
+			servers
+				.into_iter()
+				.map(|server| server.to_lowercase())
+				.collect(),
+		);
+		self
+	}
+	pub fn duplicate_login(&self) -> DuplicateLoginPolicy {
+		self.duplicate_login
+	}
+	pub fn forwarding(&self) -> Forwarding {
+		self.forwarding
+	}
+	pub fn proxy_protocol(&self) -> bool {
+		self.proxy_protocol
+	}
+	pub fn username_rules(&self) -> &UsernameRules {
+		&self.username_rules
+	}
+	pub fn login_timeout(&self) -> Duration {
+		self.login_timeout.unwrap_or(DEFAULT_LOGIN_TIMEOUT)
+	}
+	pub fn events(&self) -> &EventBus {
+		&self.events
+	}
+	pub fn packets(&self) -> &PacketHooks {
+		&self.packets
+	}
+	pub fn commands(&self) -> &Commands {
+		&self.commands
+	}
+	pub fn permissions(&self) -> &Permissions {
+		&self.permissions
+	}
+	pub fn has_permission(&self, player: &ConnectedPlayer, node: &str) -> bool {
+		self.permissions.has_permission(&player.uuid, node)
+	}
+	/// Можно ли игроку с этим UUID на сервер
+	pub fn can_join(&self, uuid: &str, target: &TargetServer) -> bool {
+		let server = target.handshake_address.to_lowercase();
+		!self.restricted_servers.contains(&server)
+			|| self
+				.permissions
+				.has_permission(uuid, &format!("proxy.server.{}", server))
+	}
+
+	pub fn players(&self) -> Vec<Arc<ConnectedPlayer>> {
+		self.players
+			.read()
+			.unwrap()
+			.by_uuid
+			.values()
+			.cloned()
+			.collect()
+	}
+	pub fn player_count(&self) -> usize {
+		self.players.read().unwrap().by_uuid.len()
+	}
+	/// Поиск без учёта регистра
+	pub fn player_by_name(&self, name: &str) -> Option<Arc<ConnectedPlayer>> {
+		self.players
+			.read()
+			.unwrap()
+			.by_name
+			.get(&name.to_lowercase())
+			.cloned()
+	}
+	pub fn player_by_uuid(&self, uuid: &str) -> Option<Arc<ConnectedPlayer>> {
+		self.players.read().unwrap().by_uuid.get(uuid).cloned()
+	}
+
+	/// Проверка дубликатов и добавление под одной блокировкой, два одновременных входа
+	/// с одним именем не пройдут оба. Ok - вытесненные игроки, их сессии нужно завершить
+	pub(crate) fn try_register(
+		&self,
+		player: Arc<ConnectedPlayer>,
+		policy: DuplicateLoginPolicy,
+	) -> Result<Vec<Arc<ConnectedPlayer>>, AlreadyConnected> {
+		let mut players = self.players.write().unwrap();
+		let name = player.username.to_lowercase();
+		let mut existing = Vec::<Arc<ConnectedPlayer>>::new();
+		for other in players
+			.by_name
+			.get(&name)
+			.into_iter()
+			.chain(players.by_uuid.get(&player.uuid))
+		{
+			if !existing.iter().any(|e| Arc::ptr_eq(e, other)) {
+				existing.push(other.clone());
+			}
+		}
+		if !existing.is_empty() && policy == DuplicateLoginPolicy::RefuseNew {
+			return Err(AlreadyConnected);
+		}
+		// Вытесненный игрок не должен находиться и по второму ключу
+		for other in &existing {
+			players.remove(other);
+		}
+		players.by_name.insert(name, player.clone());
+		players.by_uuid.insert(player.uuid.clone(), player);
+		Ok(existing)
+	}
+	/// Убирает именно этого игрока, если его место ещё не занято новой сессией
+	pub(crate) fn unregister(&self, player: &Arc<ConnectedPlayer>) {
+		self.players.write().unwrap().remove(player);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn player(username: &str, uuid: &str) -> Arc<ConnectedPlayer> {
+		let info = LoggedInInfo {
+			username: username.to_owned(),
+			uuid: uuid.to_owned(),
+			protocol: 340,
+			handshake_address: "localhost".to_owned(),
+			properties: Vec::new(),
+		};
+		Arc::new(ConnectedPlayer::new(
+			&info,
+			"127.0.0.1:50000".parse().unwrap(),
+			SessionHandle::detached(),
+		))
+	}
+
+	fn is(found: Option<Arc<ConnectedPlayer>>, player: &Arc<ConnectedPlayer>) -> bool {
+		matches!(found, Some(found) if Arc::ptr_eq(&found, player))
+	}
+
+	#[test]
+	fn name_match_ignores_case() {
+		let proxy = ProxyServer::new();
+		let old = player("Notch", "uuid-1");
+		let new = player("NOTCH", "uuid-2");
+		proxy
+			.try_register(old.clone(), DuplicateLoginPolicy::KickExisting)
+			.unwrap();
+		let replaced = proxy
+			.try_register(new.clone(), DuplicateLoginPolicy::KickExisting)
+			.unwrap();
+		assert_eq!(replaced.len(), 1);
+		assert!(Arc::ptr_eq(&replaced[0], &old));
+		assert!(is(proxy.player_by_name("notch"), &new));
+		assert!(proxy.player_by_uuid("uuid-1").is_none());
+	}
+
+	#[test]
+	fn same_uuid_under_other_name() {
+		let proxy = ProxyServer::new();
+		let old = player("Notch", "uuid-1");
+		let new = player("Renamed", "uuid-1");
+		proxy
+			.try_register(old.clone(), DuplicateLoginPolicy::KickExisting)
+			.unwrap();
+		let replaced = proxy
+			.try_register(new.clone(), DuplicateLoginPolicy::KickExisting)
+			.unwrap();
+		assert_eq!(replaced.len(), 1);
+		assert!(Arc::ptr_eq(&replaced[0], &old));
+		// Вытесненный игрок не остаётся под старым именем
+		assert!(proxy.player_by_name("Notch").is_none());
+		assert!(is(proxy.player_by_uuid("uuid-1"), &new));
+	}
+
+	#[test]
+	fn refuse_new_keeps_existing() {
+		let proxy = ProxyServer::new();
+		let old = player("Notch", "uuid-1");
+		proxy
+			.try_register(old.clone(), DuplicateLoginPolicy::RefuseNew)
+			.unwrap();
+		for new in [player("notch", "uuid-2"), player("Other", "uuid-1")] {
+			assert!(proxy
+				.try_register(new, DuplicateLoginPolicy::RefuseNew)
+				.is_err());
+		}
+		assert!(is(proxy.player_by_name("Notch"), &old));
+		assert!(is(proxy.player_by_uuid("uuid-1"), &old));
+		assert!(proxy.player_by_name("Other").is_none());
+		// Без совпадений пускает
+		let other = player("Other", "uuid-3");
+		assert!(proxy
+			.try_register(other, DuplicateLoginPolicy::RefuseNew)
+			.unwrap()
+			.is_empty());
+	}
+
+	#[test]
+	fn unregister_leaves_newer_session() {
+		let proxy = ProxyServer::new();
+		let old = player("Notch", "uuid-1");
+		let new = player("Notch", "uuid-1");
+		proxy
+			.try_register(old.clone(), DuplicateLoginPolicy::KickExisting)
+			.unwrap();
+		proxy
+			.try_register(new.clone(), DuplicateLoginPolicy::KickExisting)
+			.unwrap();
+		// Старая сессия завершается уже после входа новой
+		proxy.unregister(&old);
+		assert!(is(proxy.player_by_name("Notch"), &new));
+		assert!(is(proxy.player_by_uuid("uuid-1"), &new));
+		proxy.unregister(&new);
+		assert!(proxy.player_by_name("Notch").is_none());
+		assert!(proxy.player_by_uuid("uuid-1").is_none());
+	}
+
+	#[test]
+	fn vanilla_usernames() {
+		let rules = UsernameRules::default();
+		for name in &["Notch", "jeb_", "abc", "A_1234567890_bcd", "___"] {
+			assert!(rules.is_valid(name), "{:?} must be valid", name);
+		}
+		for name in &[
+			"",
+			"ab",
+			"A_1234567890_bcde",
+			"with space",
+			"dash-name",
+			"Привет",
+			"name\0",
+			"ＡＢＣ",
+		] {
+			assert!(!rules.is_valid(name), "{:?} must be invalid", name);
+		}
+	}
+
+	#[test]
+	fn custom_usernames() {
+		let rules = UsernameRules {
+			min_length: 1,
+			max_length: 6,
+			extra_chars: "-.".to_owned(),
+		};
+		assert!(rules.is_valid("a"));
+		assert!(rules.is_valid("a-b.c"));
+		assert!(!rules.is_valid("abcdefg"));
+		assert!(!rules.is_valid("a b"));
+		// Длина считается в символах, а не байтах
+		let rules = UsernameRules {
+			extra_chars: "é".to_owned(),
+			..UsernameRules::default()
+		};
+		assert!(rules.is_valid("éééééééééééééééé"));
+		assert!(!rules.is_valid("ééééééééééééééééé"));
+	}
+}
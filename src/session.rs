@@ -0,0 +1,639 @@
+use std::{
+	fmt::{self, Display},
+	net::SocketAddr,
+	sync::Arc,
+	time::Duration,
+};
+
+use futures::future::{BoxFuture, FutureExt};
+use tokio::{
+	io::{self, AsyncWriteExt},
+	net::TcpStream,
+	select,
+	sync::mpsc,
+	time::timeout,
+};
+
+use crate::{
+	chat,
+	client_state::ClientState,
+	command::{CommandContext, Completion},
+	event::{ChatEvent, DisconnectEvent, PostLoginEvent, ServerConnectedEvent, ServerKickEvent},
+	ext::*,
+	intercept::{Direction, InterceptedPacket},
+	limbo::{Limbo, LimboError},
+	open_any_server_connection,
+	plugin::{Plugin, RouteReason, TargetServer},
+	protocol::{
+		login::{self, LoginSuccess, SetCompression},
+		play::{
+			self, ChatRequest, ChatResponse, JoinGame, TabCompleteRequest, TabCompleteResponse,
+			Title,
+		},
+		Packet,
+	},
+	route_player,
+	server::{ConnectedPlayer, ProxyServer},
+	ConnectedServerInfo, LoggedInInfo, ServerConnectionError, SWITCH_TIMEOUT, THRESHOLD,
+};
+
+/// Этап жизни сессии игрока
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionState {
+	/// Игрок авторизовался, идёт подключение к первому серверу
+	Connecting,
+	/// Игрок в лимбо прокси, подключение к серверу ещё не начато
+	Limbo,
+	/// Игрок играет на сервере
+	Playing,
+	/// Игрок играет, параллельно идёт подключение к другому серверу
+	Switching,
+	/// Соединения закрываются
+	Disconnecting,
+}
+
+/// Чем закончилась сессия
+#[derive(Debug)]
+pub enum SessionEnd {
+	/// Клиент сам закрыл соединение
+	ClientClosed,
+	/// Прокси отключила игрока, причина - json компонент
+	Disconnected(String),
+	/// Ошибка при общении с клиентом, либо при разборе пакетов
+	Error(io::Error),
+}
+impl Display for SessionEnd {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			SessionEnd::ClientClosed => write!(f, "client closed the connection"),
+			SessionEnd::Disconnected(reason) => write!(f, "disconnected: {}", reason),
+			SessionEnd::Error(e) => write!(f, "io error: {}", e),
+		}
+	}
+}
+
+/// Команда в сессию игрока извне
+#[derive(Debug)]
+pub enum SessionCommand {
+	/// Перекинуть игрока на другой сервер, игрок остаётся на текущем, пока новый его не примет
+	Connect(TargetServer),
+	/// Отключить игрока, причина - json компонент
+	Kick(String),
+	/// Сообщение в чат, json компонент
+	Chat(String),
+	/// Сообщение над хотбаром, json компонент
+	ActionBar(String),
+	Title {
+		title: String,
+		subtitle: Option<String>,
+	},
+	/// Пакет клиенту как есть: ID и тело
+	ToClient(i32, Vec<u8>),
+	/// Пакет текущему серверу как есть: ID и тело
+	ToServer(i32, Vec<u8>),
+}
+
+/// Позволяет управлять сессией игрока из любого места
+#[derive(Clone)]
+pub struct SessionHandle {
+	sender: mpsc::UnboundedSender<SessionCommand>,
+}
+#[cfg(test)]
+impl SessionHandle {
+	/// Ни к какой сессии не привязан, команды никуда не доходят
+	pub(crate) fn detached() -> Self {
+		let (sender, _) = mpsc::unbounded_channel();
+		Self { sender }
+	}
+}
+impl SessionHandle {
+	/// false - сессия уже завершилась
+	pub fn send(&self, command: SessionCommand) -> bool {
+		self.sender.send(command).is_ok()
+	}
+	pub fn connect(&self, target: TargetServer) -> bool {
+		self.send(SessionCommand::Connect(target))
+	}
+	pub fn kick(&self, reason: &str) -> bool {
+		self.send(SessionCommand::Kick(chat::text(reason)))
+	}
+	pub fn message(&self, message: &str) -> bool {
+		self.send(SessionCommand::Chat(chat::text(message)))
+	}
+	/// Ждёт, пока сессия завершится и уберёт игрока из списка
+	pub async fn closed(&self) {
+		self.sender.closed().await
+	}
+}
+
+/// Что произошло за время общения с текущим сервером
+enum CommunicateResult {
+	ClientClosed,
+	/// Прокси отключает игрока, причина - json компонент
+	Disconnected(String),
+	/// Сервер кикнул игрока либо закрыл соединение, причина - json компонент
+	Kicked(String),
+	/// Новый сервер уже принял игрока, можно переключаться
+	Switched(TcpStream, ConnectedServerInfo),
+}
+
+pub struct Session<'p, P> {
+	user: TcpStream,
+	info: LoggedInInfo,
+	client_addr: SocketAddr,
+	plugin: &'p P,
+	client: ClientState,
+	state: SessionState,
+	handle: SessionHandle,
+	commands: mpsc::UnboundedReceiver<SessionCommand>,
+	proxy: ProxyServer,
+	player: Arc<ConnectedPlayer>,
+	/// Игрок занял своё место в списке игроков прокси
+	registered: bool,
+}
+
+impl<'p, P: Plugin> Session<'p, P> {
+	pub fn new(
+		user: TcpStream,
+		info: LoggedInInfo,
+		client_addr: SocketAddr,
+		plugin: &'p P,
+		proxy: ProxyServer,
+	) -> Self {
+		let (sender, commands) = mpsc::unbounded_channel();
+		let handle = SessionHandle { sender };
+		let player = Arc::new(ConnectedPlayer::new(&info, client_addr, handle.clone()));
+		Self {
+			user,
+			info,
+			client_addr,
+			plugin,
+			client: ClientState::default(),
+			state: SessionState::Connecting,
+			handle,
+			commands,
+			proxy,
+			player,
+			registered: false,
+		}
+	}
+
+	pub fn info(&self) -> &LoggedInInfo {
+		&self.info
+	}
+
+	pub fn handle(&self) -> SessionHandle {
+		self.handle.clone()
+	}
+
+	/// Проводит игрока через все этапы сессии, выходит после закрытия соединений с клиентом и сервером
+	pub async fn run(mut self) -> SessionEnd {
+		let end = match self.play().await {
+			Ok(end) => end,
+			Err(e) => SessionEnd::Error(e),
+		};
+		let mut reason = None;
+		if let SessionEnd::Disconnected(r) = &end {
+			let _ = self.kick(r.clone()).await;
+			reason = Some(r.clone());
+		}
+		self.state = SessionState::Disconnecting;
+		if self.registered {
+			self.proxy.unregister(&self.player);
+			self.proxy
+				.events()
+				.fire(DisconnectEvent {
+					player: self.player.clone(),
+					reason,
+				})
+				.await;
+		}
+		let _ = self.user.shutdown().await;
+		end
+	}
+
+	/// Занимает имя и UUID игрока на прокси. Вытесненная сессия успевает закрыться до того,
+	/// как новая подключится к серверу
+	async fn register(&mut self) -> Result<(), SessionEnd> {
+		let replaced = self
+			.proxy
+			.try_register(self.player.clone(), self.proxy.duplicate_login())
+			.map_err(|_| {
+				SessionEnd::Disconnected(chat::text("You are already connected to this proxy"))
+			})?;
+		self.registered = true;
+		for old in replaced {
+			old.handle().kick("You logged in from another location");
+			if timeout(SWITCH_TIMEOUT, old.handle().closed())
+				.await
+				.is_err()
+			{
+				println!("Replaced session of {} didn't close in time", old.username);
+			}
+		}
+		self.proxy
+			.events()
+			.fire(PostLoginEvent {
+				player: self.player.clone(),
+			})
+			.await;
+		Ok(())
+	}
+
+	/// Отправляет пакет отключения, подходящий для текущего этапа
+	async fn kick(&mut self, reason: String) -> io::Result<()> {
+		match self.state {
+			SessionState::Connecting => {
+				self.user
+					.write_packet(None, &login::Disconnect { reason })
+					.await
+			}
+			SessionState::Limbo | SessionState::Playing | SessionState::Switching => {
+				self.user
+					.write_packet(Some(THRESHOLD), &play::Disconnect { reason })
+					.await
+			}
+			SessionState::Disconnecting => Ok(()),
+		}
+	}
+
+	async fn play(&mut self) -> io::Result<SessionEnd> {
+		let mut limbo = Limbo::new(
+			&mut self.user,
+			&mut self.client,
+			&mut self.commands,
+			&self.info,
+			self.player.clone(),
+		);
+		let result = self.plugin.limbo(&mut limbo).await;
+		let in_limbo = limbo.entered();
+		if in_limbo {
+			self.state = SessionState::Limbo;
+		}
+		match result {
+			Ok(()) => {}
+			Err(LimboError::Io(e)) => return Err(e),
+			Err(LimboError::Closed) => return Ok(SessionEnd::ClientClosed),
+			Err(LimboError::Disconnect(reason)) => return Ok(SessionEnd::Disconnected(reason)),
+		}
+		// Только после лимбо: игрок, не знающий пароля, не должен вытеснять настоящего
+		if let Err(end) = self.register().await {
+			return Ok(end);
+		}
+
+		let targets = match route_player(
+			self.plugin,
+			&self.proxy,
+			&self.info,
+			self.client_addr,
+			RouteReason::InitialJoin,
+		)
+		.await
+		{
+			Ok(targets) => targets,
+			Err(reason) => return Ok(SessionEnd::Disconnected(chat::text(&reason))),
+		};
+		let (mut server, mut server_info) =
+			match self.connect(targets, self.proxy.login_timeout()).await {
+				Ok(connection) => connection,
+				Err(e) => return Ok(SessionEnd::Disconnected(e.into_reason())),
+			};
+		if !in_limbo {
+			self.user
+				.write_packet(
+					None,
+					&SetCompression {
+						threshold: THRESHOLD.into(),
+					},
+				)
+				.await?;
+			self.user
+				.write_packet(
+					Some(THRESHOLD),
+					&LoginSuccess {
+						username: self.info.username.clone(),
+						uuid: self.info.uuid.clone(),
+					},
+				)
+				.await?;
+		}
+		self.state = SessionState::Playing;
+
+		loop {
+			println!("Server connected");
+			self.player.set_server(Some(server_info.target.clone()));
+			self.proxy
+				.events()
+				.fire(ServerConnectedEvent {
+					player: self.player.clone(),
+					server: server_info.target.clone(),
+				})
+				.await;
+			let result = self.communicate(&mut server).await;
+			let _ = server.shutdown().await;
+			// Незавершённое переключение отменяется вместе с communicate
+			self.state = SessionState::Playing;
+			match result? {
+				CommunicateResult::ClientClosed => return Ok(SessionEnd::ClientClosed),
+				CommunicateResult::Disconnected(reason) => {
+					return Ok(SessionEnd::Disconnected(reason))
+				}
+				CommunicateResult::Switched(new_server, new_server_info) => {
+					server = new_server;
+					server_info = new_server_info;
+				}
+				CommunicateResult::Kicked(reason) => {
+					let event = self
+						.proxy
+						.events()
+						.fire(ServerKickEvent {
+							player: self.player.clone(),
+							server: server_info.target.clone(),
+							reason,
+							redirect: None,
+							cancelled: false,
+						})
+						.await;
+					let reason = event.reason;
+					if event.cancelled {
+						return Ok(SessionEnd::Disconnected(reason));
+					}
+					let fallback = match event.redirect {
+						Some(redirect) => self.connect(vec![redirect], SWITCH_TIMEOUT).await.ok(),
+						None => self.fallback(&server_info.target, &reason).await,
+					};
+					match fallback {
+						Some((new_server, new_server_info)) => {
+							self.user
+								.write_packet(
+									Some(THRESHOLD),
+									&ChatResponse {
+										message: chat::prefixed("You were kicked: ", &reason),
+										position: 0,
+									},
+								)
+								.await?;
+							server = new_server;
+							server_info = new_server_info;
+						}
+						None => return Ok(SessionEnd::Disconnected(reason)),
+					}
+				}
+			}
+		}
+	}
+
+	/// Ищет сервер, на который можно перекинуть кикнутого игрока
+	async fn fallback(
+		&self,
+		current: &TargetServer,
+		reason: &str,
+	) -> Option<(TcpStream, ConnectedServerInfo)> {
+		let kick = RouteReason::Kick {
+			server: current.clone(),
+			reason: reason.to_owned(),
+		};
+		let targets = route_player(self.plugin, &self.proxy, &self.info, self.client_addr, kick)
+			.await
+			.ok()?
+			.into_iter()
+			.filter(|target| target != current)
+			.collect();
+		self.connect(targets, SWITCH_TIMEOUT).await.ok()
+	}
+
+	/// `limit` - на все попытки вместе: сервер может принять соединение и молчать
+	async fn connect(
+		&self,
+		targets: Vec<TargetServer>,
+		limit: Duration,
+	) -> Result<(TcpStream, ConnectedServerInfo), ServerConnectionError> {
+		let connection = open_any_server_connection(&self.proxy, &self.player, &self.info, targets);
+		timeout(limit, connection)
+			.await
+			.unwrap_or(Err(ServerConnectionError::TimedOut))
+	}
+
+	/// Проводит общение юзера с сервером, пока одна из сторон не закроет соединение, либо не произойдёт переключение
+	async fn communicate(&mut self, server: &mut TcpStream) -> io::Result<CommunicateResult> {
+		let compression = Some(THRESHOLD);
+		let Self {
+			user,
+			info,
+			client_addr,
+			plugin,
+			client,
+			state,
+			commands,
+			proxy,
+			player,
+			..
+		} = self;
+		let (info, plugin, client_addr, proxy, player): (&LoggedInInfo, &P, _, &ProxyServer, &_) =
+			(info, plugin, *client_addr, proxy, player);
+		let (mut server_read, mut server_write) = server.split();
+		let (mut user_read, mut user_write) = user.split();
+
+		let mut s_peek_buf = [0];
+		let mut u_peek_buf = [0];
+		let mut packet_buf = Vec::new();
+		// Подключение к новому серверу идёт параллельно, пока игрок остаётся на текущем
+		let mut switch: Option<BoxFuture<'_, Result<(TcpStream, ConnectedServerInfo), String>>> =
+			None;
+		// Варианты прокси, которые нужно добавить к ответу сервера на Tab-Complete
+		let mut completions: Option<Vec<String>> = None;
+
+		loop {
+			// Если есть пакет от сервера - шлём пакет от сервера
+			// Есть от клиента - шлём от клиента
+			// Есть эвент - шлём эвент
+			select! {
+				Some(command) = commands.recv() => {
+					match command {
+						SessionCommand::Connect(requested) => {
+							if switch.is_some() {
+								user_write.write_packet(compression, &ChatResponse {
+									message: chat::text("Already connecting to another server"),
+									position: 0,
+								}).await?;
+								continue;
+							}
+							switch = Some(switch_server(plugin, proxy, player, info, client_addr, requested).boxed());
+							*state = SessionState::Switching;
+						}
+						SessionCommand::Kick(reason) => return Ok(CommunicateResult::Disconnected(reason)),
+						SessionCommand::Chat(message) => {
+							user_write.write_packet(compression, &ChatResponse { message, position: 0 }).await?;
+						}
+						SessionCommand::ActionBar(message) => {
+							user_write.write_packet(compression, &ChatResponse { message, position: 2 }).await?;
+						}
+						SessionCommand::Title { title, subtitle } => {
+							if let Some(subtitle) = subtitle {
+								user_write.write_packet(compression, &Title {
+									action: Title::SET_SUBTITLE.into(),
+									text: subtitle,
+								}).await?;
+							}
+							user_write.write_packet(compression, &Title {
+								action: Title::SET_TITLE.into(),
+								text: title,
+							}).await?;
+						}
+						SessionCommand::ToClient(id, data) => {
+							user_write.write_packet_data(id, compression, &data).await?;
+						}
+						SessionCommand::ToServer(id, data) => {
+							server_write.write_packet_data(id, compression, &data).await?;
+						}
+					}
+				}
+				result = async { switch.as_mut().unwrap().await }, if switch.is_some() => {
+					switch = None;
+					*state = SessionState::Playing;
+					match result {
+						Ok((server, server_info)) => return Ok(CommunicateResult::Switched(server, server_info)),
+						Err(reason) => {
+							user_write.write_packet(compression, &ChatResponse {
+								message: chat::prefixed("Could not connect: ", &reason),
+								position: 0,
+							}).await?;
+						}
+					}
+				}
+				read = server_read.peek(&mut s_peek_buf) => {
+					if !matches!(read, Ok(n) if n > 0) {
+						return Ok(CommunicateResult::Kicked(chat::text("Server closed the connection")));
+					}
+					let mut packet = server_read.read_packet(compression, &mut packet_buf).await?;
+					match packet.id().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))? {
+						play::Disconnect::ID => {
+							let disconnect = packet.decode::<play::Disconnect>()?;
+							return Ok(CommunicateResult::Kicked(disconnect.reason));
+						}
+						JoinGame::ID => {
+							let join = packet.decode::<JoinGame>()?;
+							client.join(join, compression, &mut user_write).await?;
+						}
+						TabCompleteResponse::ID if completions.is_some() => {
+							let mut response = packet.decode::<TabCompleteResponse>()?;
+							for suggestion in completions.take().unwrap() {
+								if !response.matches.contains(&suggestion) {
+									response.matches.push(suggestion);
+								}
+							}
+							user_write.write_packet(compression, &response).await?;
+						}
+						id if proxy.packets().intercepts(Direction::Clientbound, id) => {
+							let intercepted = InterceptedPacket::new(player.clone(), Direction::Clientbound, id, packet.into_data()?);
+							for (id, data) in proxy.packets().handle(intercepted).await.into_packets() {
+								client.track(id, &MaybeCompressed::Plain { packet_id: id, data: &data })?;
+								let data = if client.entities.rewrites_clientbound(id) {
+									client.entities.rewrite_clientbound(id, &data)?
+								} else {
+									data
+								};
+								user_write.write_packet_data(id, compression, &data).await?;
+							}
+						}
+						id => {
+							client.track(id, &packet)?;
+							if client.entities.rewrites_clientbound(id) {
+								let data = client.entities.rewrite_clientbound(id, &packet.into_data()?)?;
+								user_write.write_packet_data(id, compression, &data).await?;
+							} else {
+								packet.write(compression, &mut user_write).await?;
+							}
+						}
+					}
+				}
+				read = user_read.peek(&mut u_peek_buf) => {
+					if read? == 0 {
+						return Ok(CommunicateResult::ClientClosed);
+					}
+					let mut packet = user_read.read_packet(compression, &mut packet_buf).await?;
+					match packet.id().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))? {
+						ChatRequest::ID => {
+							let chat = packet.decode::<ChatRequest>()?;
+							println!("Got chat");
+							let event = proxy.events().fire(ChatEvent {
+								player: player.clone(),
+								message: chat.message,
+								cancelled: false,
+							}).await;
+							let chat = ChatRequest { message: event.message };
+							if event.cancelled {
+								continue;
+							}
+							if let Some(line) = chat.message.strip_prefix('/') {
+								let ctx = CommandContext { player: player.clone(), proxy: proxy.clone() };
+								if proxy.commands().dispatch(ctx, line) {
+									continue;
+								}
+							}
+							server_write.write_packet(compression, &chat).await?;
+						}
+						TabCompleteRequest::ID => {
+							let request = packet.decode::<TabCompleteRequest>()?;
+							// Дополнение в командном блоке, команды прокси там не работают
+							if !request.assume_command {
+								let ctx = CommandContext { player: player.clone(), proxy: proxy.clone() };
+								match proxy.commands().complete(&ctx, &request.text) {
+									Completion::Proxy(matches) => {
+										user_write.write_packet(compression, &TabCompleteResponse { matches }).await?;
+										continue;
+									}
+									Completion::Merge(matches) => completions = Some(matches),
+								}
+							}
+							server_write.write_packet(compression, &request).await?;
+						}
+						id if proxy.packets().intercepts(Direction::Serverbound, id) => {
+							let intercepted = InterceptedPacket::new(player.clone(), Direction::Serverbound, id, packet.into_data()?);
+							for (id, data) in proxy.packets().handle(intercepted).await.into_packets() {
+								let data = if client.entities.rewrites_serverbound(id) {
+									client.entities.rewrite_serverbound(id, &data)?
+								} else {
+									data
+								};
+								server_write.write_packet_data(id, compression, &data).await?;
+							}
+						}
+						id if client.entities.rewrites_serverbound(id) => {
+							let data = client.entities.rewrite_serverbound(id, &packet.into_data()?)?;
+							server_write.write_packet_data(id, compression, &data).await?;
+						}
+						_ => {
+							packet.write(compression, &mut server_write).await?;
+						}
+					}
+				}
+			}
+		}
+	}
+}
+
+/// Находит и подключает новый сервер по запросу. Ошибка - json компонент с причиной
+async fn switch_server(
+	plugin: &impl Plugin,
+	proxy: &ProxyServer,
+	player: &Arc<ConnectedPlayer>,
+	info: &LoggedInInfo,
+	client_addr: SocketAddr,
+	requested: TargetServer,
+) -> Result<(TcpStream, ConnectedServerInfo), String> {
+	let targets = route_player(
+		plugin,
+		proxy,
+		info,
+		client_addr,
+		RouteReason::Command { requested },
+	)
+	.await
+	.map_err(|reason| chat::text(&reason))?;
+	let connection = open_any_server_connection(proxy, player, info, targets);
+	match timeout(SWITCH_TIMEOUT, connection).await {
+		Ok(result) => result.map_err(|e| e.into_reason()),
+		Err(_) => Err(chat::text("Connection timed out")),
+	}
+}